@@ -0,0 +1,108 @@
+//! Benchmark of batch license generation against a local mock keygen server.
+//!
+//! Each request to the mock server takes `LATENCY` to answer, to stand in for the round trip
+//! to api.keygen.sh. Run with `cargo bench --bench generate_licenses`.
+use fastspring_keygen_integration::keygen::KeygenClient;
+use fastspring_keygen_integration::retry::RetryPolicy;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const LATENCY: Duration = Duration::from_millis(10);
+
+/// Starts the mock server and returns its base URL.
+fn start_mock_keygen() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let counter = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let counter = counter.clone();
+            thread::spawn(move || serve(stream, &counter));
+        }
+    });
+    format!("http://{}/v1", addr)
+}
+
+/// Serves the requests of a (keep-alive) connection.
+fn serve(stream: TcpStream, counter: &AtomicUsize) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    stream.set_nodelay(true).unwrap();
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+            return;
+        }
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let mut header = line.splitn(2, ':');
+            let name = header.next().unwrap_or("");
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = header.next().unwrap_or("0").trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+
+        thread::sleep(LATENCY);
+        let n = counter.fetch_add(1, Ordering::SeqCst);
+        let path = request_line.split(' ').nth(1).unwrap_or("");
+        let reply = if path.ends_with("/tokens") {
+            json!({ "data": { "type": "tokens", "attributes": { "token": format!("tok{}", n) } } })
+        } else {
+            let request: Value = serde_json::from_slice(&body).unwrap();
+            json!({
+                "data": {
+                    "id": format!("lic{}", n),
+                    "type": "licenses",
+                    "attributes": { "key": request["data"]["attributes"]["key"] }
+                }
+            })
+        }
+        .to_string();
+        let response = format!(
+            "HTTP/1.1 201 Created\r\nContent-Type: application/vnd.api+json\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            reply.len(),
+            reply
+        );
+        stream.write_all(response.as_bytes()).unwrap();
+    }
+}
+
+fn main() {
+    let base_url = start_mock_keygen();
+    println!("mock keygen latency: {:?} per request", LATENCY);
+    for &quantity in &[1, 20, 200] {
+        for &concurrency in &[1, 8, 32] {
+            let keygen = KeygenClient::new(&base_url, "account", "token")
+                .with_retry_policy(RetryPolicy::none())
+                .with_concurrency(concurrency);
+            let start = Instant::now();
+            let codes = keygen
+                .generate_licenses("subscription", "policy", quantity, None, false)
+                .unwrap();
+            let elapsed = start.elapsed();
+            assert_eq!(codes.len(), quantity as usize);
+            println!(
+                "{:>4} licenses, concurrency {:>2}: {:>8.1?} ({:.1?} per license)",
+                quantity,
+                concurrency,
+                elapsed,
+                elapsed / quantity
+            );
+        }
+    }
+}
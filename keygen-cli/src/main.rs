@@ -1,5 +1,5 @@
 use clap::{App, Arg, SubCommand};
-use fastspring_keygen_integration::keygen::generate_licenses;
+use fastspring_keygen_integration::config::KeygenConfig;
 use dotenv::dotenv;
 
 const POLICY_COMMUNITY: &str = "94a3abe1-2646-4868-94fe-e2032e82c2e2";
@@ -100,13 +100,27 @@ fn main() {
             println!("    - subscription ID: {}", subscription_id.unwrap_or(""));
             println!("    - invoice ID: {}", invoice_id.unwrap_or(""));
 
-            let (licenses,errors) = generate_licenses(
+            let keygen = match KeygenConfig::load() {
+                Ok(config) => config.client(),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return;
+                }
+            };
+            let licenses = keygen.generate_licenses(
                 subscription_id.unwrap_or(""),
                 actual_policy,
                 count,
                 invoice_id,
                 dry_run,
             );
+            let licenses = match licenses {
+                Ok(licenses) => licenses,
+                Err(e) => {
+                    eprintln!("Error generating licenses (none were kept): {}", e);
+                    return;
+                }
+            };
             if !dry_run {
                 if !licenses.is_empty() {
                     use clipboard::ClipboardContext;
@@ -127,15 +141,6 @@ fn main() {
                         }
                     }
                 }
-
-                println!();
-
-                if !errors.is_empty() {
-                    println!("{} error(s) generating licenses:", errors.len());
-                    for err in errors.iter() {
-                        println!("    - {}", err);
-                    }
-                }
             }
         }
     }
@@ -0,0 +1,113 @@
+//! Standalone HTTP server, serving the same routes as the lambda (e.g. behind nginx).
+use fastspring_keygen_integration::config::Config;
+use fastspring_keygen_integration::service::{self, State};
+use chrono::Utc;
+use lambda_http::{Body, Request, Response};
+use log::{error, info};
+use std::io::Read;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Requests with a larger body are rejected.
+const MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+/// Converts a request of the server into the request type of the handlers.
+fn to_request(req: &mut tiny_http::Request) -> Result<Request, String> {
+    let mut builder = http::Request::builder();
+    builder.method(req.method().as_str()).uri(req.url());
+    for header in req.headers() {
+        builder.header(header.field.as_str().as_str(), header.value.as_str());
+    }
+    let mut bytes = Vec::new();
+    req.as_reader()
+        .take(MAX_BODY_SIZE + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|e| e.to_string())?;
+    if bytes.len() as u64 > MAX_BODY_SIZE {
+        return Err("body too large".to_string());
+    }
+    let body = match String::from_utf8(bytes) {
+        Ok(s) if s.is_empty() => Body::Empty,
+        Ok(s) => Body::Text(s),
+        Err(e) => Body::Binary(e.into_bytes()),
+    };
+    builder.body(body).map_err(|e| e.to_string())
+}
+
+/// Converts a response of the handlers into a response of the server.
+fn to_response(response: Response<Body>) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+    let (parts, body) = response.into_parts();
+    let data = match body {
+        Body::Empty => Vec::new(),
+        Body::Text(s) => s.into_bytes(),
+        Body::Binary(b) => b,
+    };
+    let mut response = tiny_http::Response::from_data(data).with_status_code(parts.status.as_u16());
+    for (name, value) in parts.headers.iter() {
+        if let Ok(header) = tiny_http::Header::from_bytes(name.as_str(), value.as_bytes()) {
+            response.add_header(header);
+        }
+    }
+    response
+}
+
+fn serve(config: &Config, state: &State, server: &tiny_http::Server) {
+    loop {
+        let mut req = match server.recv() {
+            Ok(req) => req,
+            Err(e) => {
+                error!("could not receive request: {}", e);
+                continue;
+            }
+        };
+        let response = match to_request(&mut req) {
+            Ok(request) => to_response(service::router(config, state, request)),
+            Err(e) => {
+                error!("invalid request: {}", e);
+                tiny_http::Response::from_string(e).with_status_code(400)
+            }
+        };
+        if let Err(e) = req.respond(response) {
+            error!("could not send response: {}", e);
+        }
+    }
+}
+
+/// Periodically revokes the licenses whose grace period has passed (the scheduled function of
+/// the lambda deployment).
+fn revoke_periodically(state: &State, interval: Duration) {
+    loop {
+        if let Err(e) = service::revoke_due_licenses(state, Utc::now()) {
+            error!("could not revoke licenses: {} ({})", e, e.kind());
+        }
+        thread::sleep(interval);
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+    dotenv::dotenv().ok();
+    let config = Arc::new(Config::load()?);
+    let state = Arc::new(State::new(&config));
+    let address = (config.server.host.as_str(), config.server.port);
+    let server = Arc::new(tiny_http::Server::http(address).map_err(|e| e.to_string())?);
+    info!("listening on {}:{}", config.server.host, config.server.port);
+
+    if config.server.revocation_interval_minutes > 0 {
+        let state = state.clone();
+        let interval = Duration::from_secs(config.server.revocation_interval_minutes * 60);
+        thread::spawn(move || revoke_periodically(&state, interval));
+    }
+
+    let workers: Vec<_> = (0..config.server.threads.max(1))
+        .map(|_| {
+            let (config, state, server) = (config.clone(), state.clone(), server.clone());
+            thread::spawn(move || serve(&config, &state, &server))
+        })
+        .collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
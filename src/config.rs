@@ -0,0 +1,476 @@
+//! Service configuration, loaded and validated once at startup.
+//!
+//! Values are read from environment variables, falling back to an optional TOML file given by
+//! `CONFIG_FILE`. The file is a flat table using the environment variable names as keys
+//! (case-insensitive), e.g. `keygen_account_id = "..."`.
+use crate::error::Error;
+use crate::fastspring::{FastSpringClient, FASTSPRING_API_URL};
+use crate::keygen::{DeactivationAction, KeygenClient, DEFAULT_CONCURRENCY, KEYGEN_API_URL};
+use crate::patreon::TierPolicies;
+use crate::processed_events::{
+    AwsCredentials, DynamoDbProcessedEventStore, FileProcessedEventStore,
+    MemoryProcessedEventStore, ProcessedEventStore,
+};
+use crate::replay::ReplayGuard;
+use crate::secrets::Secrets;
+use log::warn;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub keygen: KeygenConfig,
+    pub fastspring: FastSpringConfig,
+    pub patreon: PatreonConfig,
+    pub smtp: SmtpConfig,
+    pub deactivation: DeactivationConfig,
+    /// `PENDING_REVOCATIONS_FILE`: keep pending revocations in this file instead of the
+    /// license metadata
+    pub pending_revocations_file: Option<PathBuf>,
+    pub processed_events: ProcessedEventsConfig,
+    pub replay: ReplayConfig,
+    pub server: ServerConfig,
+}
+
+#[derive(Clone, Debug)]
+pub struct KeygenConfig {
+    /// `KEYGEN_API_URL` (optional)
+    pub api_url: String,
+    /// `KEYGEN_ACCOUNT_ID`
+    pub account_id: String,
+    /// `KEYGEN_ADMIN_TOKEN`
+    pub admin_token: String,
+    /// `KEYGEN_CONCURRENCY` (optional): maximum number of licenses generated at the same time
+    pub concurrency: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct FastSpringConfig {
+    /// `FASTSPRING_API_URL` (optional)
+    pub api_url: String,
+    /// `FASTSPRING_API_USERNAME`
+    pub username: String,
+    /// `FASTSPRING_API_PASSWORD`
+    pub password: String,
+    /// `FASTSPRING_WEBHOOK_SECRET`: accepted secrets, see `Secrets` for the format
+    pub webhook_secret: Secrets,
+    /// `FASTSPRING_LICENSE_GEN_PRIVATE_KEY`: accepted private keys, see `Secrets` for the format
+    pub license_gen_private_key: Secrets,
+}
+
+#[derive(Clone, Debug)]
+pub struct PatreonConfig {
+    /// `PATREON_WEBHOOK_SECRET`: accepted secrets, see `Secrets` for the format
+    pub webhook_secret: Secrets,
+    /// `MNPRX_COMMUNITY_KEYGEN_POLICY_ID`: policy of patron licenses, unless overridden by
+    /// `tier_policies`
+    pub community_policy_id: String,
+    /// `PATREON_TIER_POLICIES` (optional)
+    pub tier_policies: TierPolicies,
+    /// `PATREON_PLEDGE_DELETE_ACTION` (optional): what to do with the license of a patron who
+    /// deleted their pledge
+    pub pledge_delete_action: DeactivationAction,
+}
+
+#[derive(Clone, Debug)]
+pub struct SmtpConfig {
+    /// `SMTP_SERVER`
+    pub server: String,
+    /// `SMTP_USERNAME`
+    pub username: String,
+    /// `SMTP_PASSWORD`
+    pub password: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct DeactivationConfig {
+    /// `DEACTIVATION_ACTION` (optional)
+    pub action: DeactivationAction,
+    /// `DEACTIVATION_POLICY_ACTIONS` (optional): per-policy overrides of `action`, as
+    /// `<policy id>=<action>,...`
+    pub policy_actions: HashMap<String, DeactivationAction>,
+    /// `FAILED_CHARGES_BEFORE_SUSPENSION` (optional, default 3)
+    pub failed_charges_before_suspension: u32,
+    /// `REVOCATION_GRACE_PERIOD_DAYS` (optional, default 7, at most 3650): number of days between the
+    /// deactivation of a subscription and the revocation of its licenses (for policies whose
+    /// deactivation action is "revoke")
+    pub revocation_grace_period_days: i64,
+}
+
+/// Where the IDs of processed webhook events are kept: in a DynamoDB table if
+/// `PROCESSED_EVENTS_TABLE` is set, else in a file if `PROCESSED_EVENTS_FILE` is set, else in
+/// memory.
+#[derive(Clone, Debug)]
+pub struct ProcessedEventsConfig {
+    /// `PROCESSED_EVENTS_FILE` (optional)
+    pub file: Option<PathBuf>,
+    /// `PROCESSED_EVENTS_TABLE` (optional)
+    pub dynamodb: Option<DynamoDbConfig>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DynamoDbConfig {
+    /// `PROCESSED_EVENTS_TABLE`
+    pub table: String,
+    /// `AWS_REGION`
+    pub region: String,
+    /// `DYNAMODB_ENDPOINT` (optional): e.g. the URL of DynamoDB Local
+    pub endpoint: Option<String>,
+    /// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN` (optional)
+    pub credentials: AwsCredentials,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReplayConfig {
+    /// `WEBHOOK_MAX_AGE_SECONDS` (optional, at most a year): reject FastSpring webhooks whose
+    /// newest event is older than this. Must be longer than the period over which FastSpring redelivers
+    /// unacknowledged events.
+    pub max_age_seconds: Option<i64>,
+    /// `REPLAY_CACHE_SECONDS` (optional, default 86400, at most a year): how long license
+    /// generation and Patreon requests are remembered to reject replays (or to reply the
+    /// licenses issued the first time), 0 to disable
+    pub cache_seconds: i64,
+}
+
+/// Settings of the standalone server (unused by the lambda).
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// `SERVER_HOST` (optional, default 127.0.0.1)
+    pub host: String,
+    /// `SERVER_PORT` (optional, default 8080)
+    pub port: u16,
+    /// `SERVER_THREADS` (optional, default 4): number of requests handled at the same time
+    pub threads: usize,
+    /// `REVOCATION_INTERVAL_MINUTES` (optional, default 60): how often licenses whose grace
+    /// period has passed are revoked, 0 to disable
+    pub revocation_interval_minutes: u64,
+}
+
+impl Config {
+    /// Loads the configuration, reporting all missing or invalid values at once.
+    pub fn load() -> Result<Config, Error> {
+        let mut source = Source::load()?;
+        let config = Config {
+            keygen: KeygenConfig::read(&mut source),
+            fastspring: FastSpringConfig::read(&mut source),
+            patreon: PatreonConfig::read(&mut source),
+            smtp: SmtpConfig::read(&mut source),
+            deactivation: DeactivationConfig::read(&mut source),
+            pending_revocations_file: source.optional("PENDING_REVOCATIONS_FILE").map(Into::into),
+            processed_events: ProcessedEventsConfig::read(&mut source),
+            replay: ReplayConfig::read(&mut source),
+            server: ServerConfig::read(&mut source),
+        };
+        source.finish()?;
+        config.warn_expired_secrets();
+        Ok(config)
+    }
+
+    /// Warns about settings whose secrets have all expired, since all the requests checked
+    /// with them are rejected.
+    fn warn_expired_secrets(&self) {
+        let secrets = [
+            ("FASTSPRING_WEBHOOK_SECRET", &self.fastspring.webhook_secret),
+            ("FASTSPRING_LICENSE_GEN_PRIVATE_KEY", &self.fastspring.license_gen_private_key),
+            ("PATREON_WEBHOOK_SECRET", &self.patreon.webhook_secret),
+        ];
+        for (name, secrets) in secrets.iter() {
+            if secrets.active().next().is_none() {
+                warn!("{}: all secrets have expired, requests will be rejected", name);
+            }
+        }
+    }
+}
+
+impl KeygenConfig {
+    /// Loads only the keygen configuration (for tools that don't need the rest).
+    pub fn load() -> Result<KeygenConfig, Error> {
+        let mut source = Source::load()?;
+        let config = KeygenConfig::read(&mut source);
+        source.finish()?;
+        Ok(config)
+    }
+
+    fn read(source: &mut Source) -> KeygenConfig {
+        KeygenConfig {
+            api_url: source
+                .optional("KEYGEN_API_URL")
+                .unwrap_or_else(|| KEYGEN_API_URL.to_string()),
+            account_id: source.required("KEYGEN_ACCOUNT_ID"),
+            admin_token: source.required("KEYGEN_ADMIN_TOKEN"),
+            concurrency: source.parse_or("KEYGEN_CONCURRENCY", DEFAULT_CONCURRENCY),
+        }
+    }
+
+    pub fn client(&self) -> KeygenClient {
+        KeygenClient::new(&self.api_url, &self.account_id, &self.admin_token)
+            .with_concurrency(self.concurrency)
+    }
+}
+
+impl FastSpringConfig {
+    fn read(source: &mut Source) -> FastSpringConfig {
+        FastSpringConfig {
+            api_url: source
+                .optional("FASTSPRING_API_URL")
+                .unwrap_or_else(|| FASTSPRING_API_URL.to_string()),
+            username: source.required("FASTSPRING_API_USERNAME"),
+            password: source.required("FASTSPRING_API_PASSWORD"),
+            webhook_secret: source.parse_required("FASTSPRING_WEBHOOK_SECRET"),
+            license_gen_private_key: source.parse_required("FASTSPRING_LICENSE_GEN_PRIVATE_KEY"),
+        }
+    }
+
+    pub fn client(&self) -> FastSpringClient {
+        FastSpringClient::new(&self.api_url, &self.username, &self.password)
+    }
+}
+
+impl PatreonConfig {
+    fn read(source: &mut Source) -> PatreonConfig {
+        PatreonConfig {
+            webhook_secret: source.parse_required("PATREON_WEBHOOK_SECRET"),
+            community_policy_id: source.required("MNPRX_COMMUNITY_KEYGEN_POLICY_ID"),
+            tier_policies: source.parse_or("PATREON_TIER_POLICIES", TierPolicies::default()),
+            pledge_delete_action: source
+                .parse_or("PATREON_PLEDGE_DELETE_ACTION", DeactivationAction::default()),
+        }
+    }
+}
+
+impl SmtpConfig {
+    fn read(source: &mut Source) -> SmtpConfig {
+        SmtpConfig {
+            server: source.required("SMTP_SERVER"),
+            username: source.required("SMTP_USERNAME"),
+            password: source.required("SMTP_PASSWORD"),
+        }
+    }
+}
+
+impl DeactivationConfig {
+    fn read(source: &mut Source) -> DeactivationConfig {
+        DeactivationConfig {
+            action: source.parse_or("DEACTIVATION_ACTION", DeactivationAction::default()),
+            policy_actions: source.parse_with(
+                "DEACTIVATION_POLICY_ACTIONS",
+                HashMap::new(),
+                parse_policy_actions,
+            ),
+            failed_charges_before_suspension: source
+                .parse_or("FAILED_CHARGES_BEFORE_SUSPENSION", 3),
+            revocation_grace_period_days: source.parse_with(
+                "REVOCATION_GRACE_PERIOD_DAYS",
+                7,
+                |v| parse_in_range(v, 0..=MAX_GRACE_PERIOD_DAYS),
+            ),
+        }
+    }
+
+    /// Returns the deactivation action for licenses of a policy.
+    pub fn action_for_policy(&self, policy: &str) -> DeactivationAction {
+        self.policy_actions
+            .get(policy)
+            .copied()
+            .unwrap_or(self.action)
+    }
+}
+
+impl ProcessedEventsConfig {
+    fn read(source: &mut Source) -> ProcessedEventsConfig {
+        let dynamodb = source
+            .optional("PROCESSED_EVENTS_TABLE")
+            .map(|table| DynamoDbConfig {
+                table,
+                region: source.required("AWS_REGION"),
+                endpoint: source.optional("DYNAMODB_ENDPOINT"),
+                credentials: AwsCredentials {
+                    access_key_id: source.required("AWS_ACCESS_KEY_ID"),
+                    secret_access_key: source.required("AWS_SECRET_ACCESS_KEY"),
+                    session_token: source.optional("AWS_SESSION_TOKEN"),
+                },
+            });
+        ProcessedEventsConfig {
+            file: source.optional("PROCESSED_EVENTS_FILE").map(Into::into),
+            dynamodb,
+        }
+    }
+
+    pub fn store(&self) -> Box<dyn ProcessedEventStore + Send + Sync> {
+        match (&self.dynamodb, &self.file) {
+            (Some(db), _) => Box::new(DynamoDbProcessedEventStore::new(
+                &db.table,
+                &db.region,
+                db.endpoint.as_deref(),
+                db.credentials.clone(),
+            )),
+            (None, Some(path)) => Box::new(FileProcessedEventStore::new(path)),
+            (None, None) => Box::new(MemoryProcessedEventStore::new()),
+        }
+    }
+}
+
+impl ReplayConfig {
+    fn read(source: &mut Source) -> ReplayConfig {
+        ReplayConfig {
+            max_age_seconds: source.parse_with("WEBHOOK_MAX_AGE_SECONDS", None, |v| {
+                parse_in_range(v, 1..=MAX_DURATION_SECONDS).map(Some)
+            }),
+            cache_seconds: source.parse_with("REPLAY_CACHE_SECONDS", 86400, |v| {
+                parse_in_range(v, 0..=MAX_DURATION_SECONDS)
+            }),
+        }
+    }
+
+    pub fn guard(&self) -> ReplayGuard {
+        ReplayGuard::new(
+            self.max_age_seconds.map(chrono::Duration::seconds),
+            chrono::Duration::seconds(self.cache_seconds),
+        )
+    }
+}
+
+impl ServerConfig {
+    fn read(source: &mut Source) -> ServerConfig {
+        ServerConfig {
+            host: source
+                .optional("SERVER_HOST")
+                .unwrap_or_else(|| "127.0.0.1".to_string()),
+            port: source.parse_or("SERVER_PORT", 8080),
+            threads: source.parse_or("SERVER_THREADS", 4),
+            revocation_interval_minutes: source.parse_or("REVOCATION_INTERVAL_MINUTES", 60),
+        }
+    }
+}
+
+/// Upper bound of `REVOCATION_GRACE_PERIOD_DAYS` (10 years).
+const MAX_GRACE_PERIOD_DAYS: i64 = 3650;
+
+/// Upper bound of the durations in seconds (1 year).
+const MAX_DURATION_SECONDS: i64 = 365 * 24 * 3600;
+
+/// Parses an integer within `range`, so that durations built from it cannot overflow.
+fn parse_in_range(s: &str, range: RangeInclusive<i64>) -> Result<i64, String> {
+    let n: i64 = s.trim().parse().map_err(|e: std::num::ParseIntError| e.to_string())?;
+    if !range.contains(&n) {
+        return Err(format!("must be between {} and {}", range.start(), range.end()));
+    }
+    Ok(n)
+}
+
+/// Parses `<policy id>=<action>,...`.
+fn parse_policy_actions(s: &str) -> Result<HashMap<String, DeactivationAction>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut kv = entry.splitn(2, '=');
+            let policy = kv.next().unwrap_or("").trim();
+            let action = kv
+                .next()
+                .ok_or_else(|| format!("invalid entry `{}`", entry))?
+                .parse()?;
+            Ok((policy.to_string(), action))
+        })
+        .collect()
+}
+
+/// Raw configuration values by key, collecting the errors of all lookups.
+struct Source {
+    values: HashMap<String, String>,
+    errors: Vec<String>,
+}
+
+impl Source {
+    /// Reads the file given by `CONFIG_FILE` (if any), then the environment.
+    fn load() -> Result<Source, Error> {
+        let mut values = HashMap::new();
+        if let Ok(path) = env::var("CONFIG_FILE") {
+            values = read_file(Path::new(&path))?;
+        }
+        values.extend(env::vars());
+        Ok(Source {
+            values,
+            errors: Vec::new(),
+        })
+    }
+
+    fn optional(&self, key: &str) -> Option<String> {
+        self.values.get(key).filter(|v| !v.is_empty()).cloned()
+    }
+
+    fn required(&mut self, key: &str) -> String {
+        self.optional(key).unwrap_or_else(|| {
+            self.errors.push(format!("`{}` is not set", key));
+            String::new()
+        })
+    }
+
+    fn parse_required<T>(&mut self, key: &str) -> T
+    where
+        T: FromStr + Default,
+        T::Err: Display,
+    {
+        if self.optional(key).is_none() {
+            self.errors.push(format!("`{}` is not set", key));
+        }
+        self.parse_or(key, T::default())
+    }
+
+    fn parse_or<T>(&mut self, key: &str, default: T) -> T
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.parse_with(key, default, |v| v.parse().map_err(|e: T::Err| e.to_string()))
+    }
+
+    fn parse_with<T>(
+        &mut self,
+        key: &str,
+        default: T,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> T {
+        match self.optional(key) {
+            Some(v) => parse(&v).unwrap_or_else(|e| {
+                self.errors.push(format!("`{}` is invalid: {}", key, e));
+                default
+            }),
+            None => default,
+        }
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::config(self.errors.join(", ")))
+        }
+    }
+}
+
+/// Reads a flat TOML table, with keys converted to upper case.
+fn read_file(path: &Path) -> Result<HashMap<String, String>, Error> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| Error::config(format!("could not read {}: {}", path.display(), e)))?;
+    let table: toml::value::Table = toml::from_str(&contents)
+        .map_err(|e| Error::config(format!("invalid {}: {}", path.display(), e)))?;
+    table
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                toml::Value::String(s) => s,
+                toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => {
+                    value.to_string()
+                }
+                _ => return Err(Error::config(format!("`{}` must be a string", key))),
+            };
+            Ok((key.to_ascii_uppercase(), value))
+        })
+        .collect()
+}
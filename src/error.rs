@@ -0,0 +1,155 @@
+//! Errors returned by the request handlers.
+use crate::fastspring::FastSpringError;
+use crate::keygen::KeygenError;
+use crate::retry::Transient;
+use crate::util::ParseError;
+use http::StatusCode;
+use lambda_runtime::error::HandlerError;
+use serde_json::json;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request signature is missing or does not match
+    InvalidSignature,
+    /// The request was already received, or is too old
+    Replayed(String),
+    /// The request body or parameters don't have the expected format
+    MalformedPayload(String),
+    /// A keygen request failed
+    Keygen(KeygenError),
+    /// A FastSpring request failed
+    FastSpring(FastSpringError),
+    /// The license email could not be sent
+    Email(String),
+    /// Missing or invalid configuration
+    Config(String),
+    /// Any other failure (e.g. reading the pending revocations)
+    Internal(String),
+}
+
+impl Error {
+    pub fn malformed(message: impl Into<String>) -> Error {
+        Error::MalformedPayload(message.into())
+    }
+
+    pub fn config(message: impl Into<String>) -> Error {
+        Error::Config(message.into())
+    }
+
+    pub fn internal(message: impl Into<String>) -> Error {
+        Error::Internal(message.into())
+    }
+
+    /// Short name of the error, used in the response body.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::InvalidSignature => "invalid_signature",
+            Error::Replayed(_) => "replayed",
+            Error::MalformedPayload(_) => "malformed_payload",
+            Error::Keygen(_) => "keygen",
+            Error::FastSpring(_) => "fastspring",
+            Error::Email(_) => "email",
+            Error::Config(_) => "config",
+            Error::Internal(_) => "internal",
+        }
+    }
+
+    /// Returns whether the same request may succeed later (e.g. an upstream outage).
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Keygen(e) => e.is_transient(),
+            Error::FastSpring(e) => e.is_transient(),
+            Error::Email(_) | Error::Internal(_) => true,
+            Error::InvalidSignature
+            | Error::Replayed(_)
+            | Error::MalformedPayload(_)
+            | Error::Config(_) => false,
+        }
+    }
+
+    /// Returns whether the error is caused by the payload itself (e.g. a license that keygen
+    /// doesn't know), so that delivering it again would fail the same way. Authentication and
+    /// configuration errors are not, they go away once the configuration is fixed.
+    pub fn is_caused_by_payload(&self) -> bool {
+        match self {
+            Error::MalformedPayload(_) => true,
+            Error::Keygen(e) => matches!(
+                e.status(),
+                Some(StatusCode::NOT_FOUND) | Some(StatusCode::UNPROCESSABLE_ENTITY)
+            ),
+            _ => false,
+        }
+    }
+
+    /// Status of the response to the webhook.
+    ///
+    /// Errors of the sender are 4xx so that they are not retried. Upstream errors are 503 if
+    /// retrying is worthwhile, 502 otherwise.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::InvalidSignature => StatusCode::UNAUTHORIZED,
+            Error::Replayed(_) => StatusCode::CONFLICT,
+            Error::MalformedPayload(_) => StatusCode::BAD_REQUEST,
+            Error::Keygen(_) | Error::FastSpring(_) if self.is_transient() => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            Error::Keygen(_) | Error::FastSpring(_) => StatusCode::BAD_GATEWAY,
+            Error::Email(_) | Error::Config(_) | Error::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// JSON body of the response to the webhook.
+    pub fn body(&self) -> serde_json::Value {
+        json!({
+            "error": {
+                "kind": self.kind(),
+                "message": self.to_string(),
+                "retry": self.is_transient(),
+            }
+        })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidSignature => write!(f, "invalid signature"),
+            Error::Replayed(msg) => write!(f, "replayed request: {}", msg),
+            Error::MalformedPayload(msg) => write!(f, "{}", msg),
+            Error::Keygen(e) => write!(f, "{}", e),
+            Error::FastSpring(e) => write!(f, "{}", e),
+            Error::Email(msg) => write!(f, "could not send email: {}", msg),
+            Error::Config(msg) => write!(f, "invalid configuration: {}", msg),
+            Error::Internal(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Error {
+        Error::MalformedPayload(e.to_string())
+    }
+}
+
+impl From<KeygenError> for Error {
+    fn from(e: KeygenError) -> Error {
+        Error::Keygen(e)
+    }
+}
+
+impl From<FastSpringError> for Error {
+    fn from(e: FastSpringError) -> Error {
+        Error::FastSpring(e)
+    }
+}
+
+impl From<Error> for HandlerError {
+    fn from(e: Error) -> HandlerError {
+        e.to_string().as_str().into()
+    }
+}
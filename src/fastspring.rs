@@ -1,27 +1,25 @@
-use lambda_http::{Body, Request};
-use lambda_runtime::error::HandlerError;
-use lazy_static::lazy_static;
-use log::{debug, error, info};
+use crate::retry::{self, RetryPolicy, Transient};
+use crate::secrets::Secrets;
+use crate::util::{body_bytes, constant_time_eq};
+use chrono::NaiveDate;
+use lambda_http::Request;
+use log::{error, info};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use reqwest::StatusCode;
 use std::collections::HashMap;
-use std::env;
-
-lazy_static! {
-    static ref FASTSPRING_API_USERNAME: String = env::var("FASTSPRING_API_USERNAME").unwrap();
-    static ref FASTSPRING_API_PASSWORD: String = env::var("FASTSPRING_API_PASSWORD").unwrap();
-    static ref FASTSPRING_WEBHOOK_SECRET: String = env::var("FASTSPRING_WEBHOOK_SECRET").unwrap();
-    static ref FASTSPRING_LICENSE_GEN_PRIVATE_KEY: String =
-        env::var("FASTSPRING_LICENSE_GEN_PRIVATE_KEY").unwrap();
-}
+use std::fmt;
+use std::time::Duration;
+
+pub mod events;
 
-pub fn verify_license_gen(req: &Request) -> bool {
+use self::events::{Account, Order, Subscription, SubscriptionEntry};
+
+pub fn verify_license_gen(req: &Request, private_keys: &Secrets) -> bool {
     // collect query parameters
     let mut sig = "";
     let mut p = Vec::new();
-    let params: HashMap<_, _> = url::form_urlencoded::parse(match req.body() {
-        Body::Text(ref s) => s.as_bytes(),
-        _ => return false,
-    })
-    .collect();
+    let params: HashMap<_, _> = url::form_urlencoded::parse(body_bytes(req.body())).collect();
 
     for (k, v) in params.iter() {
         if k == "security_request_hash" {
@@ -35,28 +33,28 @@ pub fn verify_license_gen(req: &Request) -> bool {
 
     // print query string
     let mut qstr = String::new();
+    #[allow(clippy::needless_range_loop)]
     for i in 0..p.len() {
         qstr.push_str(p[i].1);
     }
-    // append private key
-    qstr.push_str(&*FASTSPRING_LICENSE_GEN_PRIVATE_KEY);
-
-    // MD5 hash
-    let digest = md5::compute(qstr.as_bytes());
-    let digest = format!("{:032x}", digest);
-    debug!("sig={}, digest={}", sig, &digest[..]);
-
-    // compare
-    let ok = sig == &digest[..];
-    if ok {
-        info!("verify_license_gen: authenticated request from FastSpring");
-    } else {
-        error!("verify_license_gen: signature check failed");
+    // MD5 hash of the query string followed by the private key
+    let matched = private_keys.find(|private_key| {
+        let digest = md5::compute(format!("{}{}", qstr, private_key).as_bytes());
+        constant_time_eq(sig.as_bytes(), format!("{:032x}", digest).as_bytes())
+    });
+    match matched {
+        Some(key) => {
+            info!("verify_license_gen: authenticated request from FastSpring with {}", key);
+            true
+        }
+        None => {
+            error!("verify_license_gen: signature check failed");
+            false
+        }
     }
-    ok
 }
 
-pub fn authentify_web_hook(req: &Request) -> bool {
+pub fn authentify_web_hook(req: &Request, secrets: &Secrets) -> bool {
     // get auth header
     let hash = if let Some(h) = req
         .headers()
@@ -69,40 +67,213 @@ pub fn authentify_web_hook(req: &Request) -> bool {
         return false;
     };
 
-    let calc_hash = base64::encode(&hmac_sha256::HMAC::mac(
-        req.body(),
-        FASTSPRING_WEBHOOK_SECRET.as_bytes(),
-    ));
-
-    debug!(
-        "authentify_web_hook: hash={}, calc_hash={}",
-        hash, calc_hash
-    );
     // compare with header
-    let ok = hash == calc_hash;
-    if ok {
-        info!("authentify_web_hook: authenticated web hook from FastSpring");
-    } else {
-        error!("authentify_web_hook: signature check failed");
+    let matched = secrets.find(|secret| {
+        let calc_hash = base64::encode(&hmac_sha256::HMAC::mac(body_bytes(req.body()), secret.as_bytes()));
+        constant_time_eq(hash.as_bytes(), calc_hash.as_bytes())
+    });
+    match matched {
+        Some(secret) => {
+            info!("authentify_web_hook: authenticated web hook from FastSpring with {}", secret);
+            true
+        }
+        None => {
+            error!("authentify_web_hook: signature check failed");
+            false
+        }
+    }
+}
+
+/// Default base URL of the FastSpring API.
+pub const FASTSPRING_API_URL: &str = "https://api.fastspring.com";
+
+/// Error returned by `FastSpringClient`.
+#[derive(Debug)]
+pub enum FastSpringError {
+    /// The request could not be sent or the reply could not be read
+    Request(String),
+    /// FastSpring replied with a non-2xx status
+    Status {
+        status: StatusCode,
+        /// Value of the `Retry-After` header
+        retry_after: Option<Duration>,
+    },
+    /// The reply is not what we expected
+    InvalidReply(String),
+}
+
+impl Transient for FastSpringError {
+    fn is_transient(&self) -> bool {
+        match self {
+            FastSpringError::Request(_) => true,
+            FastSpringError::Status { status, .. } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            FastSpringError::InvalidReply(_) => false,
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            FastSpringError::Status { retry_after, .. } => *retry_after,
+            _ => None,
+        }
     }
-    ok
 }
 
-/// Returns subscription info
-pub fn get_subscription_entries(
-    client: &reqwest::Client,
-    id: &str,
-) -> Result<serde_json::Value, HandlerError> {
-    let reply = client
-        .get(&format!(
-            "https://api.fastspring.com/subscriptions/{}/entries",
-            id
-        ))
-        .basic_auth(&*FASTSPRING_API_USERNAME, Some(&*FASTSPRING_API_PASSWORD))
-        .send()
-        .map_err(|_| "request error")?
-        .json()
-        .map_err(|_| "invalid json")?;
-
-    Ok(reply)
+impl fmt::Display for FastSpringError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FastSpringError::Request(msg) => write!(f, "fastspring request error: {}", msg),
+            FastSpringError::Status { status, .. } => write!(f, "fastspring replied {}", status),
+            FastSpringError::InvalidReply(msg) => write!(f, "invalid fastspring reply: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for FastSpringError {}
+
+/// Page of results of the order lookup by date range.
+#[derive(Deserialize)]
+struct OrderPage {
+    #[serde(default)]
+    orders: Vec<Order>,
+    #[serde(rename = "nextPage", default)]
+    next_page: Option<u32>,
+}
+
+/// Client for the FastSpring API.
+#[derive(Clone, Debug)]
+pub struct FastSpringClient {
+    client: reqwest::Client,
+    base_url: String,
+    username: String,
+    password: String,
+    retry: RetryPolicy,
+}
+
+impl FastSpringClient {
+    /// Creates a client for the FastSpring API at `base_url` (e.g. `https://api.fastspring.com`).
+    pub fn new(base_url: &str, username: &str, password: &str) -> FastSpringClient {
+        FastSpringClient {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// Sets the retry policy of requests (all requests to the API are idempotent).
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> FastSpringClient {
+        self.retry = retry;
+        self
+    }
+
+    /// Sends an authenticated GET request and parses the reply, retrying on transient errors.
+    fn get<T: DeserializeOwned>(
+        &self,
+        what: &str,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> Result<T, FastSpringError> {
+        self.retry
+            .run(&format!("fastspring {} request", what), || self.get_once(what, path, query))
+    }
+
+    fn get_once<T: DeserializeOwned>(
+        &self,
+        what: &str,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> Result<T, FastSpringError> {
+        let mut reply = self
+            .client
+            .get(&format!("{}/{}", self.base_url, path))
+            .query(query)
+            .basic_auth(&self.username, Some(&self.password))
+            .send()
+            .map_err(|e| FastSpringError::Request(e.to_string()))?;
+        if !reply.status().is_success() {
+            return Err(FastSpringError::Status {
+                status: reply.status(),
+                retry_after: retry::retry_after(reply.headers()),
+            });
+        }
+        let body = reply
+            .text()
+            .map_err(|e| FastSpringError::Request(e.to_string()))?;
+
+        events::from_str(what, &body).map_err(|e| FastSpringError::InvalidReply(e.to_string()))
+    }
+
+    /// Returns an order by ID
+    pub fn get_order(&self, id: &str) -> Result<Order, FastSpringError> {
+        self.get("order", &format!("orders/{}", id), &[])
+    }
+
+    /// Returns an order by reference (e.g. `ART190101-1234-56789`)
+    pub fn get_order_by_reference(&self, reference: &str) -> Result<Order, FastSpringError> {
+        // the order endpoint accepts references as well as IDs
+        let order: Order = self.get("order", &format!("orders/{}", reference), &[])?;
+        if order.reference.as_deref() != Some(reference) {
+            return Err(FastSpringError::InvalidReply(format!(
+                "order reference mismatch ({})",
+                reference
+            )));
+        }
+        Ok(order)
+    }
+
+    /// Returns the orders placed between two dates (inclusive), going through all pages
+    pub fn list_orders(
+        &self,
+        begin: NaiveDate,
+        end: NaiveDate,
+    ) -> Result<Vec<Order>, FastSpringError> {
+        const PAGE_SIZE: u32 = 50;
+
+        let mut orders = Vec::new();
+        let mut page = 1;
+        loop {
+            let reply: OrderPage = self.get(
+                "orders",
+                "orders",
+                &[
+                    ("begin", begin.format("%Y-%m-%d").to_string()),
+                    ("end", end.format("%Y-%m-%d").to_string()),
+                    ("limit", PAGE_SIZE.to_string()),
+                    ("page", page.to_string()),
+                ],
+            )?;
+            orders.extend(reply.orders);
+            match reply.next_page {
+                Some(next) if next > page => page = next,
+                _ => break,
+            }
+        }
+        Ok(orders)
+    }
+
+    /// Returns a subscription by ID
+    pub fn get_subscription(&self, id: &str) -> Result<Subscription, FastSpringError> {
+        self.get("subscription", &format!("subscriptions/{}", id), &[])
+    }
+
+    /// Returns the orders associated with a subscription
+    pub fn get_subscription_entries(
+        &self,
+        id: &str,
+    ) -> Result<Vec<SubscriptionEntry>, FastSpringError> {
+        self.get(
+            "subscription entries",
+            &format!("subscriptions/{}/entries", id),
+            &[],
+        )
+    }
+
+    /// Returns an account by ID
+    pub fn get_account(&self, id: &str) -> Result<Account, FastSpringError> {
+        self.get("account", &format!("accounts/{}", id), &[])
+    }
 }
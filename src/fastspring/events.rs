@@ -0,0 +1,349 @@
+//! Typed FastSpring webhook payloads.
+pub use crate::util::{from_str, from_value, ParseError};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Body of a webhook request: a batch of events.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Events {
+    pub events: Vec<RawEvent>,
+}
+
+impl Events {
+    pub fn parse(body: &str) -> Result<Events, ParseError> {
+        from_str("events", body)
+    }
+}
+
+/// Event envelope, with the payload not yet parsed.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RawEvent {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+    #[serde(default)]
+    pub live: bool,
+    #[serde(default)]
+    pub processed: bool,
+    #[serde(default)]
+    pub created: Option<u64>,
+    #[serde(default)]
+    pub data: Value,
+}
+
+impl RawEvent {
+    /// Parses the event payload according to the event type.
+    pub fn parse(&self) -> Result<Event, ParseError> {
+        let data = self.data.clone();
+        let ty = self.ty.as_str();
+        Ok(match ty {
+            "subscription.activated" => Event::SubscriptionActivated(from_value(ty, data)?),
+            "subscription.deactivated" => Event::SubscriptionDeactivated(from_value(ty, data)?),
+            "subscription.canceled" => Event::SubscriptionCanceled(from_value(ty, data)?),
+            "subscription.uncanceled" => Event::SubscriptionUncanceled(from_value(ty, data)?),
+            "subscription.updated" => Event::SubscriptionUpdated(from_value(ty, data)?),
+            "subscription.charge.completed" => {
+                Event::SubscriptionChargeCompleted(from_value(ty, data)?)
+            }
+            "subscription.charge.failed" => Event::SubscriptionChargeFailed(from_value(ty, data)?),
+            "return.created" => Event::ReturnCreated(from_value(ty, data)?),
+            "order.completed" => Event::OrderCompleted(from_value(ty, data)?),
+            "account.created" => Event::AccountCreated(from_value(ty, data)?),
+            "account.updated" => Event::AccountUpdated(from_value(ty, data)?),
+            _ => Event::Unhandled(self.ty.clone()),
+        })
+    }
+}
+
+/// A parsed webhook event.
+#[derive(Clone, Debug)]
+pub enum Event {
+    SubscriptionActivated(Subscription),
+    SubscriptionDeactivated(Subscription),
+    SubscriptionCanceled(Subscription),
+    SubscriptionUncanceled(Subscription),
+    SubscriptionUpdated(Subscription),
+    SubscriptionChargeCompleted(Charge),
+    SubscriptionChargeFailed(Charge),
+    OrderCompleted(Order),
+    ReturnCreated(Return),
+    AccountCreated(Account),
+    AccountUpdated(Account),
+    /// Event type we don't parse
+    Unhandled(String),
+}
+
+/// Reference to another object: either its ID, or the expanded object itself
+/// (depending on the "expand" setting of the webhook).
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Ref<T> {
+    Id(String),
+    Expanded(T),
+}
+
+impl<T: HasId> Ref<T> {
+    pub fn id(&self) -> &str {
+        match self {
+            Ref::Id(id) => id,
+            Ref::Expanded(obj) => obj.id(),
+        }
+    }
+}
+
+pub trait HasId {
+    fn id(&self) -> &str;
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Subscription {
+    pub id: String,
+    #[serde(default)]
+    pub active: bool,
+    #[serde(default)]
+    pub state: Option<String>,
+    #[serde(default)]
+    pub live: bool,
+    #[serde(default)]
+    pub account: Option<Ref<Account>>,
+    #[serde(default)]
+    pub product: Option<Value>,
+    #[serde(default)]
+    pub sku: Option<String>,
+    #[serde(default)]
+    pub quantity: Option<u32>,
+    #[serde(default)]
+    pub next: Option<i64>,
+    #[serde(default)]
+    pub end: Option<i64>,
+    #[serde(default)]
+    pub canceled_date: Option<i64>,
+    #[serde(default)]
+    pub deactivation_date: Option<i64>,
+}
+
+impl HasId for Subscription {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// Payload of `subscription.charge.*` events.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Charge {
+    pub subscription: Ref<Subscription>,
+    #[serde(default)]
+    pub order: Option<Ref<Order>>,
+    #[serde(default)]
+    pub status: Option<String>,
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub currency: Option<String>,
+    #[serde(default)]
+    pub total: Option<f64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Order {
+    pub id: String,
+    #[serde(default)]
+    pub reference: Option<String>,
+    #[serde(default)]
+    pub live: bool,
+    #[serde(default)]
+    pub completed: bool,
+    #[serde(default)]
+    pub changed: Option<i64>,
+    #[serde(default)]
+    pub account: Option<Ref<Account>>,
+    #[serde(default)]
+    pub items: Vec<Item>,
+}
+
+impl HasId for Order {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Order {
+    /// Returns whether this is a subscription billing order (reference ending with "B")
+    /// rather than the order that created the subscription.
+    pub fn is_rebill(&self) -> bool {
+        match self.reference {
+            Some(ref r) => r.ends_with('B'),
+            None => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Item {
+    #[serde(default)]
+    pub product: Option<String>,
+    #[serde(default)]
+    pub quantity: u32,
+    #[serde(default)]
+    pub sku: Option<String>,
+    #[serde(default)]
+    pub subscription: Option<Ref<Subscription>>,
+    #[serde(default)]
+    pub fulfillments: BTreeMap<String, Fulfillment>,
+}
+
+impl Item {
+    /// Returns all license codes fulfilled for this item.
+    pub fn licenses(&self) -> Vec<&str> {
+        self.fulfillments
+            .values()
+            .flat_map(|f| f.licenses())
+            .collect()
+    }
+}
+
+/// Entry of the `fulfillments` map of an item. Besides license lists, this map also
+/// contains other things such as instructions, which we don't care about.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Fulfillment {
+    Licenses(Vec<FulfillmentEntry>),
+    Other(Value),
+}
+
+impl Fulfillment {
+    pub fn licenses(&self) -> Vec<&str> {
+        match self {
+            Fulfillment::Licenses(entries) => entries
+                .iter()
+                .filter_map(|e| e.license.as_deref())
+                .collect(),
+            Fulfillment::Other(_) => Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct FulfillmentEntry {
+    #[serde(default)]
+    pub license: Option<String>,
+    #[serde(default)]
+    pub display: Option<String>,
+    #[serde(rename = "type", default)]
+    pub ty: Option<String>,
+}
+
+/// Payload of `return.created` events: a refund or a chargeback.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Return {
+    #[serde(rename = "return")]
+    pub id: String,
+    #[serde(default)]
+    pub reference: Option<String>,
+    /// `RETURN` or `CHARGEBACK`
+    #[serde(rename = "type", default)]
+    pub ty: Option<String>,
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// The order being returned
+    pub original: ReturnOriginal,
+    #[serde(default)]
+    pub items: Vec<ReturnItem>,
+}
+
+impl Return {
+    pub fn is_chargeback(&self) -> bool {
+        match self.ty {
+            Some(ref ty) => ty.eq_ignore_ascii_case("chargeback"),
+            None => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReturnOriginal {
+    pub id: String,
+    #[serde(default)]
+    pub reference: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReturnItem {
+    #[serde(default)]
+    pub product: Option<String>,
+    /// Returned quantity, `None` if the whole item is returned
+    #[serde(default)]
+    pub quantity: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Account {
+    pub id: String,
+    #[serde(default)]
+    pub contact: Option<Contact>,
+    #[serde(default)]
+    pub language: Option<String>,
+    #[serde(default)]
+    pub country: Option<String>,
+}
+
+impl HasId for Account {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Contact {
+    #[serde(default)]
+    pub first: Option<String>,
+    #[serde(default)]
+    pub last: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub company: Option<String>,
+}
+
+/// Entry returned by the `/subscriptions/{id}/entries` endpoint.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriptionEntry {
+    /// Start of the period paid by this order (milliseconds since the epoch)
+    #[serde(default)]
+    pub begin_period_date: Option<i64>,
+    /// End of the period paid by this order (milliseconds since the epoch)
+    #[serde(default)]
+    pub end_period_date: Option<i64>,
+    pub order: Order,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_events_report_the_field_path() {
+        let err = Events::parse(r#"{"events":[{"id":"a","type":"x"},{"id":2,"type":"x"}]}"#)
+            .unwrap_err();
+        assert_eq!(err.context, "events");
+        assert_eq!(err.path, "events[1].id");
+    }
+
+    #[test]
+    fn malformed_payloads_report_the_field_path() {
+        let events = Events::parse(
+            r#"{"events":[{"id":"a","type":"order.completed",
+                "data":{"id":"o","items":[{"product":"p","quantity":"one"}]}}]}"#,
+        )
+        .unwrap();
+        let err = events.events[0].parse().unwrap_err();
+        assert_eq!(err.context, "order.completed");
+        assert_eq!(err.path, "items[0].quantity");
+        assert!(err.to_string().contains("order.completed: .items[0].quantity"));
+    }
+}
@@ -1,190 +1,645 @@
+use crate::retry::{self, RetryPolicy, Transient};
+use chrono::{DateTime, SecondsFormat, Utc};
 use http::header::{ACCEPT, CONTENT_TYPE};
-use lambda_runtime::error::HandlerError;
-use lazy_static::lazy_static;
-use log::{debug, info};
+use log::{error, info};
 use rand::Rng;
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::Deserialize;
 use serde_json::json;
-use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 
-lazy_static! {
-    static ref KEYGEN_ADMIN_TOKEN: String = env::var("KEYGEN_ADMIN_TOKEN").expect("`KEYGEN_ADMIN_TOKEN` environment variable not set");
-    static ref KEYGEN_ACCOUNT_ID: String = env::var("KEYGEN_ACCOUNT_ID").expect("`KEYGEN_ACCOUNT_ID` environment variable not set");
-    //static ref KEYGEN_POLICY_ID: String = env::var("KEYGEN_POLICY_ID").unwrap();
+/// Default base URL of the keygen.sh API.
+pub const KEYGEN_API_URL: &str = "https://api.keygen.sh/v1";
+
+/// Default number of licenses generated at the same time.
+pub const DEFAULT_CONCURRENCY: usize = 8;
+
+/// Error object of a JSON:API error reply.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ApiError {
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub detail: Option<String>,
+    /// Keygen error code, e.g. `NOT_FOUND` or `LICENSE_NOT_SUSPENDED`
+    #[serde(default)]
+    pub code: Option<String>,
+    #[serde(default)]
+    pub source: Option<ErrorSource>,
+}
+
+/// Part of the request an `ApiError` refers to.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ErrorSource {
+    /// JSON pointer into the request body, e.g. `/data/attributes/key`
+    #[serde(default)]
+    pub pointer: Option<String>,
+    /// Query parameter
+    #[serde(default)]
+    pub parameter: Option<String>,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.title.as_deref().unwrap_or("error"))?;
+        if let Some(ref detail) = self.detail {
+            write!(f, ": {}", detail)?;
+        }
+        if let Some(ref code) = self.code {
+            write!(f, " [{}]", code)?;
+        }
+        if let Some(pointer) = self.source.as_ref().and_then(|s| s.pointer.as_ref()) {
+            write!(f, " (at {})", pointer)?;
+        }
+        Ok(())
+    }
+}
+
+/// Error returned by `KeygenClient`.
+#[derive(Debug)]
+pub enum KeygenError {
+    /// The request could not be sent or the reply could not be read
+    Request(String),
+    /// Keygen replied with a non-2xx status
+    Api {
+        status: StatusCode,
+        errors: Vec<ApiError>,
+        /// Value of the `Retry-After` header
+        retry_after: Option<Duration>,
+    },
+    /// The reply is not what we expected
+    InvalidReply(String),
+    /// Creating a license failed, and we could not check whether it was created anyway
+    Unconfirmed(Box<KeygenError>),
+}
+
+impl KeygenError {
+    /// Returns the HTTP status of the reply, if keygen replied with an error.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            KeygenError::Api { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.status() == Some(StatusCode::NOT_FOUND)
+    }
+
+    /// Returns whether one of the error objects has the given keygen error code.
+    pub fn has_code(&self, code: &str) -> bool {
+        match self {
+            KeygenError::Api { errors, .. } => {
+                errors.iter().any(|e| e.code.as_deref() == Some(code))
+            }
+            _ => false,
+        }
+    }
 }
 
-/// Suspends a license by license key.
-pub fn suspend_license(license_key: &str) -> Result<(), HandlerError> {
-    modify_license(license_key, LicenseAction::Suspend)
+impl Transient for KeygenError {
+    fn is_transient(&self) -> bool {
+        match self {
+            KeygenError::Request(_) => true,
+            KeygenError::Api { status, .. } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            KeygenError::InvalidReply(_) | KeygenError::Unconfirmed(_) => false,
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            KeygenError::Api { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
 }
 
-/*
-/// Reinstates (un-suspends) a license by license key.
-pub fn reinstate_license(license_key: &str) -> Result<(), HandlerError> {
-    modify_license(license_key, LicenseAction::Reinstate)
-}*/
+impl fmt::Display for KeygenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeygenError::Request(msg) => write!(f, "keygen request error: {}", msg),
+            KeygenError::Api { status, errors, .. } => {
+                write!(f, "keygen replied {}", status)?;
+                for (i, e) in errors.iter().enumerate() {
+                    write!(f, "{} {}", if i == 0 { ":" } else { ";" }, e)?;
+                }
+                Ok(())
+            }
+            KeygenError::InvalidReply(msg) => write!(f, "invalid keygen reply: {}", msg),
+            KeygenError::Unconfirmed(e) => {
+                write!(f, "could not confirm whether the license was created: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeygenError {}
+
+/// Returns the string at `pointer` in a keygen document, or an `InvalidReply` error.
+pub fn reply_str<'a>(reply: &'a serde_json::Value, pointer: &str) -> Result<&'a str, KeygenError> {
+    reply
+        .pointer(pointer)
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| KeygenError::InvalidReply(format!("missing {}", pointer)))
+}
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 enum LicenseAction {
     Suspend,
-    //Reinstate,
+    Reinstate,
 }
 
-fn modify_license(license_key: &str, action: LicenseAction) -> Result<(), HandlerError> {
-    let action_verb = match action {
-        LicenseAction::Suspend => "suspend",
-        //LicenseAction::Reinstate => "reinstate",
-    };
-
-    let client = reqwest::Client::new();
-    let reply = client
-        .post(&format!(
-            "https://api.keygen.sh/v1/accounts/{}/licenses/{}/actions/{}",
-            *KEYGEN_ACCOUNT_ID, license_key, action_verb
-        ))
-        .bearer_auth(&*KEYGEN_ADMIN_TOKEN)
-        .header(ACCEPT, "application/vnd.api+json")
-        .send()
-        .map_err(|_| "request error")?;
-
-    info!(
-        "{} license {} status {}",
-        action_verb,
-        license_key,
-        reply.status()
-    );
-    Ok(())
+/// Client for the license endpoints of a keygen account.
+#[derive(Clone, Debug)]
+pub struct KeygenClient {
+    client: reqwest::Client,
+    base_url: String,
+    account_id: String,
+    token: String,
+    retry: RetryPolicy,
+    concurrency: usize,
 }
 
-pub fn revoke_license(license_key: &str) -> Result<(), HandlerError> {
-    let client = reqwest::Client::new();
-    let reply = client
-        .delete(&format!(
-            "https://api.keygen.sh/v1/accounts/{}/licenses/{}",
-            *KEYGEN_ACCOUNT_ID, license_key
-        ))
-        .bearer_auth(&*KEYGEN_ADMIN_TOKEN)
-        .header(ACCEPT, "application/vnd.api+json")
-        .send()
-        .map_err(|_| "request error")?;
-
-    info!("Revoke license {} status {}", license_key, reply.status());
-    Ok(())
-}
+impl KeygenClient {
+    /// Creates a client for an account of the keygen instance at `base_url`
+    /// (e.g. `https://api.keygen.sh/v1`).
+    pub fn new(base_url: &str, account_id: &str, token: &str) -> KeygenClient {
+        KeygenClient {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            account_id: account_id.to_string(),
+            token: token.to_string(),
+            retry: RetryPolicy::default(),
+            concurrency: DEFAULT_CONCURRENCY,
+        }
+    }
 
-pub fn generate_license(client: &reqwest::Client,
-                        subscription: &str,
-                        policy: &str,
-                        invoice_id: Option<&str>,
-                        patreon_user_id: Option<&str>,
-                        dry_run: bool) -> Result<String,HandlerError>
-{
-    let mut lic = [0u8; 16];
-    let mut rng = rand::thread_rng();
-    rng.fill(&mut lic);
-    let lic = hex::encode(lic);
-
-    let req_body = json!({
-            "data": {
-                "type": "licenses",
-                "attributes": {
-                    "key": lic,
-                    "metadata": {
-                        "fastSpringSubscriptionId": subscription,
-                        "patreonUserId": patreon_user_id.unwrap_or(""),
-                        "invoiceId": invoice_id.unwrap_or("")
-                    }
-                },
-                "relationships": {
-                    "policy": {
-                        "data": { "type": "policies", "id": policy }
+    /// Sets the retry policy of idempotent requests.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> KeygenClient {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets the maximum number of licenses generated at the same time by `generate_licenses`.
+    pub fn with_concurrency(mut self, concurrency: usize) -> KeygenClient {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Returns the URL of an endpoint of the account.
+    fn url(&self, path: &str) -> String {
+        format!("{}/accounts/{}/{}", self.base_url, self.account_id, path)
+    }
+
+    /// Starts an authenticated request to an endpoint of the account.
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client
+            .request(method, &self.url(path))
+            .bearer_auth(&self.token)
+            .header(ACCEPT, "application/vnd.api+json")
+    }
+
+    /// Sends an idempotent request, retrying on transient errors.
+    fn send(&self, req: RequestBuilder) -> Result<serde_json::Value, KeygenError> {
+        self.send_action(req, |_| false)
+    }
+
+    /// Sends a request that changes the state of a license, retrying on transient errors.
+    ///
+    /// An attempt may have been applied even though its reply was lost, in which case the next
+    /// attempt fails because the license is already in that state (`already_applied`). That
+    /// error is taken as success on retries.
+    fn send_action(
+        &self,
+        req: RequestBuilder,
+        already_applied: impl Fn(&KeygenError) -> bool,
+    ) -> Result<serde_json::Value, KeygenError> {
+        let mut attempt = 0;
+        self.retry.run("keygen request", || {
+            attempt += 1;
+            let req = req
+                .try_clone()
+                .ok_or_else(|| KeygenError::Request("request cannot be retried".to_string()))?;
+            match self.send_once(req) {
+                Err(ref e) if attempt > 1 && already_applied(e) => {
+                    info!("keygen request applied by an earlier attempt: {}", e);
+                    Ok(serde_json::Value::Null)
+                }
+                result => result,
+            }
+        })
+    }
+
+    /// Sends a request and returns the reply document (`Null` if the reply has no body).
+    /// Non-2xx replies are turned into `KeygenError::Api` with the JSON:API error objects.
+    fn send_once(&self, req: RequestBuilder) -> Result<serde_json::Value, KeygenError> {
+        let mut reply = req
+            .send()
+            .map_err(|e| KeygenError::Request(e.to_string()))?;
+        let status = reply.status();
+        let retry_after = retry::retry_after(reply.headers());
+        let body = reply
+            .text()
+            .map_err(|e| KeygenError::Request(e.to_string()))?;
+
+        if !status.is_success() {
+            #[derive(Deserialize)]
+            struct ErrorReply {
+                #[serde(default)]
+                errors: Vec<ApiError>,
+            }
+            let errors = serde_json::from_str::<ErrorReply>(&body)
+                .map(|r| r.errors)
+                .unwrap_or_default();
+            return Err(KeygenError::Api {
+                status,
+                errors,
+                retry_after,
+            });
+        }
+
+        if body.trim().is_empty() {
+            return Ok(serde_json::Value::Null);
+        }
+        serde_json::from_str(&body).map_err(|e| KeygenError::InvalidReply(e.to_string()))
+    }
+
+    /// Suspends a license by license key.
+    pub fn suspend_license(&self, license_key: &str) -> Result<(), KeygenError> {
+        self.modify_license(license_key, LicenseAction::Suspend)
+    }
+
+    /// Reinstates (un-suspends) a license by license key.
+    pub fn reinstate_license(&self, license_key: &str) -> Result<(), KeygenError> {
+        self.modify_license(license_key, LicenseAction::Reinstate)
+    }
+
+    fn modify_license(&self, license_key: &str, action: LicenseAction) -> Result<(), KeygenError> {
+        let (action_verb, already_applied) = match action {
+            LicenseAction::Suspend => ("suspend", "LICENSE_ALREADY_SUSPENDED"),
+            LicenseAction::Reinstate => ("reinstate", "LICENSE_NOT_SUSPENDED"),
+        };
+        self.send_action(
+            self.request(Method::POST, &format!("licenses/{}/actions/{}", license_key, action_verb)),
+            |e| e.has_code(already_applied),
+        )?;
+
+        info!("{} license {}", action_verb, license_key);
+        Ok(())
+    }
+
+    pub fn revoke_license(&self, license_key: &str) -> Result<(), KeygenError> {
+        self.send_action(
+            self.request(Method::DELETE, &format!("licenses/{}", license_key)),
+            KeygenError::is_not_found,
+        )?;
+
+        info!("Revoke license {}", license_key);
+        Ok(())
+    }
+
+    /// Returns a license object by license key.
+    pub fn get_license(&self, license_key: &str) -> Result<serde_json::Value, KeygenError> {
+        let reply = self.send(self.request(Method::GET, &format!("licenses/{}", license_key)))?;
+
+        Ok(reply["data"].clone())
+    }
+
+    /// Returns all suspended license objects.
+    pub fn list_suspended_licenses(&self) -> Result<Vec<serde_json::Value>, KeygenError> {
+        self.list_licenses(&[("suspended".to_string(), "true".to_string())])
+    }
+
+    /// Returns the license objects whose metadata `key` is equal to `value`.
+    pub fn find_licenses_by_metadata(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<Vec<serde_json::Value>, KeygenError> {
+        self.list_licenses(&[(format!("metadata[{}]", key), value.to_string())])
+    }
+
+    /// Returns all license objects matching the given filters.
+    fn list_licenses(
+        &self,
+        filters: &[(String, String)],
+    ) -> Result<Vec<serde_json::Value>, KeygenError> {
+        const PAGE_SIZE: usize = 100;
+
+        let mut licenses = Vec::new();
+        for page in 1.. {
+            let reply = self.send(
+                self.request(Method::GET, "licenses")
+                    .query(filters)
+                    .query(&[
+                        ("page[size]", PAGE_SIZE.to_string()),
+                        ("page[number]", page.to_string()),
+                    ]),
+            )?;
+
+            let data = reply["data"]
+                .as_array()
+                .ok_or_else(|| KeygenError::InvalidReply("missing /data".to_string()))?;
+            licenses.extend(data.iter().cloned());
+            if data.len() < PAGE_SIZE {
+                break;
+            }
+        }
+        Ok(licenses)
+    }
+
+    /// Returns the metadata of a license by license key.
+    pub fn get_license_metadata(
+        &self,
+        license_key: &str,
+    ) -> Result<serde_json::Map<String, serde_json::Value>, KeygenError> {
+        let license = self.get_license(license_key)?;
+        let metadata = license["attributes"]["metadata"]
+            .as_object()
+            .ok_or_else(|| KeygenError::InvalidReply("missing license metadata".to_string()))?;
+        Ok(metadata.clone())
+    }
+
+    /// Returns the ID of the policy of a license by license key.
+    pub fn get_license_policy(&self, license_key: &str) -> Result<String, KeygenError> {
+        let license = self.get_license(license_key)?;
+        Ok(reply_str(&license, "/relationships/policy/data/id")?.to_string())
+    }
+
+    /// Moves a license to another policy, keeping its key.
+    pub fn change_license_policy(
+        &self,
+        license_key: &str,
+        policy: &str,
+    ) -> Result<(), KeygenError> {
+        let req_body = json!({
+                "data": { "type": "policies", "id": policy }
+            });
+        self.send(
+            self.request(Method::PUT, &format!("licenses/{}/policy", license_key))
+                .header(CONTENT_TYPE, "application/vnd.api+json")
+                .body(req_body.to_string()),
+        )?;
+
+        info!("Change license {} policy to {}", license_key, policy);
+        Ok(())
+    }
+
+    /// Replaces the metadata of a license by license key.
+    pub fn set_license_metadata(
+        &self,
+        license_key: &str,
+        metadata: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<(), KeygenError> {
+        self.update_license(license_key, json!({ "metadata": metadata }))
+    }
+
+    /// Sets the expiry date of a license by license key, `None` for a license that doesn't
+    /// expire.
+    pub fn set_license_expiry(
+        &self,
+        license_key: &str,
+        expiry: Option<DateTime<Utc>>,
+    ) -> Result<(), KeygenError> {
+        let expiry = expiry.map(|expiry| expiry.to_rfc3339_opts(SecondsFormat::Millis, true));
+        self.update_license(license_key, json!({ "expiry": expiry }))
+    }
+
+    fn update_license(
+        &self,
+        license_key: &str,
+        attributes: serde_json::Value,
+    ) -> Result<(), KeygenError> {
+        let req_body = json!({
+                "data": {
+                    "type": "licenses",
+                    "attributes": attributes
+                }
+            });
+        self.send(
+            self.request(Method::PATCH, &format!("licenses/{}", license_key))
+                .header(CONTENT_TYPE, "application/vnd.api+json")
+                .body(req_body.to_string()),
+        )?;
+
+        info!("Update license {}", license_key);
+        Ok(())
+    }
+
+    pub fn generate_license(&self,
+                            subscription: &str,
+                            policy: &str,
+                            invoice_id: Option<&str>,
+                            patreon_user_id: Option<&str>,
+                            dry_run: bool) -> Result<String, KeygenError>
+    {
+        let mut lic = [0u8; 16];
+        let mut rng = rand::thread_rng();
+        rng.fill(&mut lic);
+        let lic = hex::encode(lic);
+
+        let req_body = json!({
+                "data": {
+                    "type": "licenses",
+                    "attributes": {
+                        "key": lic,
+                        "metadata": {
+                            "fastSpringSubscriptionId": subscription,
+                            "patreonUserId": patreon_user_id.unwrap_or(""),
+                            "invoiceId": invoice_id.unwrap_or("")
+                        }
+                    },
+                    "relationships": {
+                        "policy": {
+                            "data": { "type": "policies", "id": policy }
+                        }
                     }
                 }
+            });
+
+        if dry_run {
+            info!("generate_licenses: DRY RUN");
+            info!(" - endpoint: {}", self.url("licenses"));
+            info!(" - body: {:#?}", req_body.to_string());
+            return Ok("".to_string());
+        }
+
+        // Creation is not idempotent: only retry once the (pre-generated) key is confirmed not to
+        // exist, or pick up the license if a failed attempt actually created it.
+        let reply = self.retry.run("license creation", || {
+            let e = match self.send_once(
+                self.request(Method::POST, "licenses")
+                    .header(CONTENT_TYPE, "application/vnd.api+json")
+                    .body(req_body.to_string()),
+            ) {
+                Ok(reply) => return Ok(reply),
+                Err(e) => e,
+            };
+            if !e.is_transient() {
+                return Err(e);
+            }
+            // a single lookup: this loop already retries
+            match self.send_once(self.request(Method::GET, &format!("licenses/{}", lic))) {
+                Ok(reply) => {
+                    info!("license {} was created despite the error: {}", lic, e);
+                    Ok(reply)
+                }
+                Err(ref lookup) if lookup.is_not_found() => Err(e),
+                Err(_) => Err(KeygenError::Unconfirmed(Box::new(e))),
             }
         });
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(e @ KeygenError::Unconfirmed(_)) => {
+                self.roll_back(&[&lic]);
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let license_id = reply_str(&reply, "/data/id")?;
+        let license_key = reply_str(&reply, "/data/attributes/key")?;
+
+        //-------------------------------------------
+        // generate activation token for license
+        let activation_token = match self.generate_activation_token(license_id) {
+            Ok(token) => token,
+            Err(e) => {
+                self.roll_back(&[license_key]);
+                return Err(e);
+            }
+        };
 
-    if dry_run {
-        info!("generate_licenses: DRY RUN");
-        info!(
-            " - endpoint: {}",
-            format!(
-                "https://api.keygen.sh/v1/accounts/{}/licenses",
-                *KEYGEN_ACCOUNT_ID
-            )
-        );
-        info!(" - body: {:#?}", req_body.to_string());
-        return Ok("".to_string());
-    }
-
-    let reply: serde_json::Value = client
-        .post(&format!(
-            "https://api.keygen.sh/v1/accounts/{}/licenses",
-            *KEYGEN_ACCOUNT_ID
-        ))
-        .bearer_auth(&*KEYGEN_ADMIN_TOKEN)
-        .header(CONTENT_TYPE, "application/vnd.api+json")
-        .header(ACCEPT, "application/vnd.api+json")
-        .body(req_body.to_string())
-        .send()
-        .map_err(|_| "request error")?
-        .json()
-        .map_err(|_| "invalid json")?;
-
-    let license_id = reply["data"]["id"].as_str().ok_or("invalid reply")?;
-    let license_key = reply["data"]["attributes"]["key"]
-        .as_str()
-        .ok_or("invalid reply")?;
-
-    //-------------------------------------------
-    // generate activation token for license
-    let req_body = json!({
-            "data": {
-                "type": "tokens",
-                "attributes": {}
+        // return activation code (activation token + license key)
+        Ok(format!("{}.{}", activation_token, license_key))
+    }
+
+    /// Generates a new activation token for a license by license ID.
+    ///
+    /// Retried like idempotent requests: a failed attempt can at worst leave an unused token.
+    pub fn generate_activation_token(&self, license_id: &str) -> Result<String, KeygenError> {
+        let req_body = json!({
+                "data": {
+                    "type": "tokens",
+                    "attributes": {}
+                }
+            });
+        let reply = self.send(
+            self.request(Method::POST, &format!("licenses/{}/tokens", license_id))
+                .header(CONTENT_TYPE, "application/vnd.api+json")
+                .body(req_body.to_string()),
+        )?;
+
+        Ok(reply_str(&reply, "/data/attributes/token")?.to_string())
+    }
+
+    /// Generates `quantity` licenses and returns their activation codes.
+    ///
+    /// Licenses are generated by up to `concurrency` threads sharing the connection pool of the
+    /// client. Either all licenses are generated, or none: if one fails, the remaining ones are
+    /// not started and the licenses already generated are deleted (along with their tokens)
+    /// before returning the error.
+    pub fn generate_licenses(
+        &self,
+        subscription: &str,
+        policy: &str,
+        quantity: u32,
+        invoice_id: Option<&str>,
+        dry_run: bool,
+    ) -> Result<Vec<String>, KeygenError>
+    {
+        let quantity = quantity as usize;
+        info!("Generating {} licenses with policy {}", quantity, policy);
+
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let results: Mutex<Vec<(usize, Result<String, KeygenError>)>> =
+            Mutex::new(Vec::with_capacity(quantity));
+        let worker = || loop {
+            if failed.load(Ordering::SeqCst) {
+                break;
+            }
+            let i = next.fetch_add(1, Ordering::SeqCst);
+            if i >= quantity {
+                break;
+            }
+            let result = self.generate_license(subscription, policy, invoice_id, None, dry_run);
+            if result.is_err() {
+                failed.store(true, Ordering::SeqCst);
+            }
+            results.lock().unwrap().push((i, result));
+        };
+        thread::scope(|scope| {
+            for _ in 0..self.concurrency.min(quantity) {
+                scope.spawn(worker);
             }
         });
-    let reply: serde_json::Value = client
-        .post(&format!(
-            "https://api.keygen.sh/v1/accounts/{}/licenses/{}/tokens",
-            *KEYGEN_ACCOUNT_ID, license_id
-        ))
-        .bearer_auth(&*KEYGEN_ADMIN_TOKEN)
-        .header(CONTENT_TYPE, "application/vnd.api+json")
-        .header(ACCEPT, "application/vnd.api+json")
-        .body(req_body.to_string())
-        .send()
-        .map_err(|_| "request error")?
-        .json()
-        .map_err(|_| "invalid json")?;
-
-    let activation_token = reply["data"]["attributes"]["token"]
-        .as_str()
-        .ok_or("invalid reply")?;
-
-    // return activation code (activation token + license key)
-    Ok(format!("{}.{}", activation_token, license_key))
-}
 
-pub fn generate_licenses(
-    subscription: &str,
-    policy: &str,
-    quantity: u32,
-    invoice_id: Option<&str>,
-    dry_run: bool,
-) -> (Vec<String>, Vec<HandlerError>)
-{
-    let client = reqwest::Client::new();
-    let mut codes = Vec::new();
-    let mut errors = Vec::new();
+        // keep the order of the codes independent of the completion order
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|(i, _)| *i);
+        let mut codes = Vec::with_capacity(quantity);
+        let mut error = None;
+        for (i, result) in results {
+            match result {
+                Ok(code) => codes.push(code),
+                Err(e) if error.is_none() => {
+                    info!("could not generate license {}/{}: {}", i + 1, quantity, e);
+                    error = Some(e);
+                }
+                Err(e) => info!("could not generate license {}/{}: {}", i + 1, quantity, e),
+            }
+        }
+        if let Some(e) = error {
+            info!("rolling back {} generated licenses", codes.len());
+            let keys: Vec<&str> = codes.iter().filter_map(|code| code.split('.').nth(1)).collect();
+            self.roll_back(&keys);
+            return Err(e);
+        }
 
-    info!("Generating {} licenses with policy {}", quantity, policy);
+        Ok(codes)
+    }
 
-    for _ in 0..quantity {
-        let code = generate_license(&client, subscription, policy, invoice_id, None, dry_run);
-        match code {
-            Ok(code) => codes.push(code),
-            Err(e) => errors.push(e)
+    /// Deletes licenses created by a failed operation. Failures are only logged.
+    fn roll_back(&self, license_keys: &[&str]) {
+        for key in license_keys {
+            match self.revoke_license(key) {
+                Ok(()) => {}
+                Err(ref e) if e.is_not_found() => {}
+                Err(e) => error!("could not roll back license {}: {}", key, e),
+            }
         }
     }
+}
+
+/// What to do with the licenses of a deactivated subscription.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum DeactivationAction {
+    /// Suspend the license (can be undone by reinstating it)
+    #[default]
+    Suspend,
+    /// Delete the license and its history
+    Revoke,
+    /// Set the license expiry to the end of the paid period
+    Expire,
+}
+
+impl FromStr for DeactivationAction {
+    type Err = String;
 
-    (codes,errors)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "suspend" => Ok(DeactivationAction::Suspend),
+            "revoke" | "delete" => Ok(DeactivationAction::Revoke),
+            "expire" => Ok(DeactivationAction::Expire),
+            other => Err(format!("invalid deactivation action `{}`", other)),
+        }
+    }
 }
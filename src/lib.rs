@@ -1,4 +1,12 @@
+pub mod config;
+pub mod error;
 pub mod fastspring;
 pub mod keygen;
 pub mod util;
 pub mod patreon;
+pub mod processed_events;
+pub mod replay;
+pub mod retry;
+pub mod revocation;
+pub mod secrets;
+pub mod service;
@@ -1,40 +1,146 @@
+use crate::secrets::Secrets;
+use crate::util::body_bytes;
 use lambda_http::Request;
-use log::{debug, error, info};
+use log::{error, info};
 use hmac::{Hmac, Mac, NewMac};
 use other_md5::Md5;
-use lazy_static::lazy_static;
-use std::env;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::str::FromStr;
 
-type HmacMd5 = Hmac<Md5>;
+pub mod members;
 
-lazy_static! {
-    static ref PATREON_WEBHOOK_SECRET: String = env::var("PATREON_WEBHOOK_SECRET").unwrap();
-}
+type HmacMd5 = Hmac<Md5>;
 
-pub fn authentify_web_hook(req: &Request) -> bool {
+pub fn authentify_web_hook(req: &Request, secrets: &Secrets) -> bool {
     // get auth header
     let signature = if let Some(h) = req
         .headers()
         .get("X-Patreon-Signature")
         .and_then(|h| h.to_str().ok())
     {
-        hex::decode(h).expect("invalid patreon signature format")
+        match hex::decode(h.trim()) {
+            Ok(signature) => signature,
+            Err(e) => {
+                error!("patreon::authentify_web_hook: invalid signature format: {}", e);
+                return false;
+            }
+        }
     } else {
         error!("patreon::authentify_web_hook: unable to authentify web hook");
         return false;
     };
 
-    let mut mac : HmacMd5 = HmacMd5::new_varkey(PATREON_WEBHOOK_SECRET.as_bytes()).unwrap();
-    mac.update(req.body().as_ref());
+    let matched = secrets.find(|secret| {
+        let mut mac : HmacMd5 = match HmacMd5::new_varkey(secret.as_bytes()) {
+            Ok(mac) => mac,
+            Err(_) => return false,
+        };
+        mac.update(body_bytes(req.body()));
+        mac.verify(&signature).is_ok()
+    });
+    match matched {
+        Some(secret) => {
+            info!(
+                "patreon::authentify_web_hook: authenticated web hook from Patreon with {}",
+                secret
+            );
+            true
+        }
+        None => {
+            error!("patreon::authentify_web_hook: signature check failed");
+            false
+        }
+    }
+}
+/// Mapping from Patreon tiers or minimum pledge amounts to keygen policies.
+///
+/// Parsed from a comma-separated list of `tier:<tier id>=<policy id>` and
+/// `amount:<minimum amount in cents>=<policy id>` entries.
+#[derive(Clone, Debug, Default)]
+pub struct TierPolicies {
+    tiers: HashMap<String, String>,
+    /// (minimum amount in cents, policy), sorted by decreasing amount
+    amounts: Vec<(u32, String)>,
+}
 
-    match mac.verify(&signature) {
-        Ok(_) => {
-            info!("patreon::authentify_web_hook: authenticated web hook from Patreon");
-            return true;
+impl TierPolicies {
+    /// Returns the policy for a pledge. Matching tiers take precedence over amounts.
+    pub fn policy(&self, tier_ids: &[&str], amount_cents: u32) -> Option<&str> {
+        if let Some(policy) = tier_ids.iter().find_map(|id| self.tiers.get(*id)) {
+            return Some(policy);
         }
-        Err(e) => {
-            error!("patreon::authentify_web_hook: signature check failed {}", e);
-            return false;
+        self.amounts
+            .iter()
+            .find(|(min, _)| amount_cents >= *min)
+            .map(|(_, policy)| policy.as_str())
+    }
+}
+
+impl FromStr for TierPolicies {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mapping = TierPolicies::default();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let invalid = || format!("invalid tier policy entry `{}`", entry);
+            let mut kv = entry.splitn(2, '=');
+            let key = kv.next().ok_or_else(invalid)?.trim();
+            let policy = kv.next().ok_or_else(invalid)?.trim().to_string();
+            if let Some(tier) = key.strip_prefix("tier:") {
+                mapping.tiers.insert(tier.to_string(), policy);
+            } else if let Some(amount) = key.strip_prefix("amount:") {
+                let amount = amount.parse().map_err(|_| invalid())?;
+                mapping.amounts.push((amount, policy));
+            } else {
+                return Err(invalid());
+            }
         }
+        mapping.amounts.sort_by_key(|(amount, _)| Reverse(*amount));
+        Ok(mapping)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policies(s: &str) -> TierPolicies {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn entries_are_parsed() {
+        let p = policies(" tier:1 = gold , amount:500=silver,,amount:100=bronze ");
+        assert_eq!(p.tiers.get("1").map(String::as_str), Some("gold"));
+        assert_eq!(
+            p.amounts,
+            vec![(500, "silver".to_string()), (100, "bronze".to_string())]
+        );
+        assert!(policies("").policy(&["1"], 1000).is_none());
+    }
+
+    #[test]
+    fn invalid_entries_are_rejected() {
+        for s in &["tier:1", "gold", "level:1=gold", "amount:five=gold", "amount:-1=gold"] {
+            assert!(s.parse::<TierPolicies>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn tiers_take_precedence_over_amounts() {
+        let p = policies("amount:100=bronze,tier:1=gold,amount:500=silver,tier:2=platinum");
+        assert_eq!(p.policy(&["1"], 1000), Some("gold"));
+        assert_eq!(p.policy(&["3", "2"], 0), Some("platinum"));
+        assert_eq!(p.policy(&["1", "2"], 0), Some("gold"));
+    }
+
+    #[test]
+    fn highest_matching_amount_is_used() {
+        let p = policies("amount:100=bronze,amount:500=silver");
+        assert_eq!(p.policy(&["3"], 1000), Some("silver"));
+        assert_eq!(p.policy(&[], 500), Some("silver"));
+        assert_eq!(p.policy(&[], 499), Some("bronze"));
+        assert_eq!(p.policy(&[], 99), None);
+    }
+}
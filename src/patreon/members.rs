@@ -0,0 +1,131 @@
+//! Typed Patreon API v2 webhook payloads (`members:*` triggers).
+use crate::util::{from_value, ParseError};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Body of a `members:*` webhook request (JSON:API document).
+#[derive(Clone, Debug, Deserialize)]
+pub struct MemberEvent {
+    pub data: Member,
+    #[serde(default)]
+    pub included: Vec<Resource>,
+}
+
+impl MemberEvent {
+    pub fn parse(trigger: &str, body: Value) -> Result<MemberEvent, ParseError> {
+        from_value(trigger, body)
+    }
+
+    /// Returns the Patreon user ID of the member.
+    pub fn user_id(&self) -> Option<&str> {
+        self.data
+            .relationships
+            .user
+            .as_ref()
+            .and_then(|user| user.data.as_ref())
+            .map(|user| user.id.as_str())
+    }
+
+    /// Returns the email of the member, falling back to the email of the included user.
+    pub fn email(&self) -> Option<&str> {
+        if let Some(ref email) = self.data.attributes.email {
+            return Some(email);
+        }
+        let user_id = self.user_id()?;
+        self.included
+            .iter()
+            .find(|r| r.ty == "user" && r.id == user_id)
+            .and_then(|user| user.attributes["email"].as_str())
+    }
+
+    /// Returns the IDs of the tiers the member is currently entitled to.
+    pub fn tier_ids(&self) -> Vec<&str> {
+        match self.data.relationships.currently_entitled_tiers {
+            Some(ref tiers) => tiers.data.iter().map(|t| t.id.as_str()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn patron_status(&self) -> Option<PatronStatus> {
+        self.data.attributes.patron_status
+    }
+
+    pub fn amount_cents(&self) -> u32 {
+        self.data
+            .attributes
+            .currently_entitled_amount_cents
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Member {
+    pub id: String,
+    pub attributes: MemberAttributes,
+    #[serde(default)]
+    pub relationships: MemberRelationships,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MemberAttributes {
+    /// `None` if the user never pledged
+    #[serde(default)]
+    pub patron_status: Option<PatronStatus>,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub full_name: Option<String>,
+    #[serde(default)]
+    pub currently_entitled_amount_cents: Option<u32>,
+    #[serde(default)]
+    pub last_charge_status: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatronStatus {
+    ActivePatron,
+    /// The last payment was declined
+    DeclinedPatron,
+    /// The member stopped pledging
+    FormerPatron,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct MemberRelationships {
+    #[serde(default)]
+    pub user: Option<ToOne>,
+    #[serde(default)]
+    pub currently_entitled_tiers: Option<ToMany>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ToOne {
+    #[serde(default)]
+    pub data: Option<ResourceId>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ToMany {
+    #[serde(default)]
+    pub data: Vec<ResourceId>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ResourceId {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+}
+
+/// Entry of the `included` array. Only users are looked at, so attributes are left untyped.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Resource {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+    #[serde(default)]
+    pub attributes: Value,
+}
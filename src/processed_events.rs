@@ -0,0 +1,514 @@
+//! Processed webhook events.
+//!
+//! FastSpring may deliver an event more than once (e.g. when the reply to a webhook is lost),
+//! so the IDs of the events being handled are recorded, and skipped when they come again.
+//! An event is claimed for a while (its lease) before it is handled, so that two concurrent
+//! deliveries don't both handle it, and recorded as done once handled. It is released if
+//! handling it fails, and a later delivery takes it over if its lease expired, e.g. because
+//! the lambda handling it crashed.
+use crate::error::Error;
+use chrono::{DateTime, Duration, Utc};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// How long a delivery may handle an event before another delivery takes it over, longer than
+/// the maximum run time of a lambda.
+const CLAIM_LEASE_SECONDS: i64 = 20 * 60;
+
+fn lease_until(now: DateTime<Utc>) -> DateTime<Utc> {
+    now + Duration::seconds(CLAIM_LEASE_SECONDS)
+}
+
+/// Result of claiming an event.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Claim {
+    /// To be handled by this delivery
+    Claimed,
+    /// Being handled by another delivery, whose lease hasn't expired
+    InProgress,
+    /// Already handled
+    Done,
+}
+
+/// Storage for the IDs of processed events.
+pub trait ProcessedEventStore {
+    /// Claims an event for handling, unless it was already handled or is being handled by
+    /// another delivery.
+    fn claim(&self, event_id: &str) -> Result<Claim, Error>;
+    /// Records a claimed event as handled.
+    fn complete(&self, event_id: &str) -> Result<(), Error>;
+    /// Forgets a claimed event, so that it is handled again when delivered again.
+    fn release(&self, event_id: &str) -> Result<(), Error>;
+}
+
+/// State of a recorded event.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum EventState {
+    /// Claimed by a delivery until the given time
+    InProgress { lease_until: DateTime<Utc> },
+    Done,
+}
+
+impl EventState {
+    /// Returns the claim of an event in this state at `now`.
+    fn claim(self, now: DateTime<Utc>) -> Claim {
+        match self {
+            EventState::InProgress { lease_until } if lease_until <= now => Claim::Claimed,
+            EventState::InProgress { .. } => Claim::InProgress,
+            EventState::Done => Claim::Done,
+        }
+    }
+}
+
+/// Keeps processed events in memory, i.e. only as long as the (warm) lambda instance lives.
+#[derive(Default)]
+pub struct MemoryProcessedEventStore {
+    events: Mutex<HashMap<String, EventState>>,
+}
+
+impl MemoryProcessedEventStore {
+    pub fn new() -> MemoryProcessedEventStore {
+        MemoryProcessedEventStore::default()
+    }
+}
+
+impl ProcessedEventStore for MemoryProcessedEventStore {
+    fn claim(&self, event_id: &str) -> Result<Claim, Error> {
+        let now = Utc::now();
+        let mut events = self.events.lock().unwrap();
+        let claim = events.get(event_id).map_or(Claim::Claimed, |state| state.claim(now));
+        if claim == Claim::Claimed {
+            let state = EventState::InProgress { lease_until: lease_until(now) };
+            events.insert(event_id.to_string(), state);
+        }
+        Ok(claim)
+    }
+
+    fn complete(&self, event_id: &str) -> Result<(), Error> {
+        self.events.lock().unwrap().insert(event_id.to_string(), EventState::Done);
+        Ok(())
+    }
+
+    fn release(&self, event_id: &str) -> Result<(), Error> {
+        self.events.lock().unwrap().remove(event_id);
+        Ok(())
+    }
+}
+
+/// Keeps processed events in a local JSON file. Intended for testing.
+pub struct FileProcessedEventStore {
+    path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProcessedEvent {
+    id: String,
+    /// When the event was claimed, or handled once done
+    processed_at: String,
+    /// Absent in files written before events were claimed with a lease
+    #[serde(default = "done")]
+    state: EventState,
+}
+
+fn done() -> EventState {
+    EventState::Done
+}
+
+impl FileProcessedEventStore {
+    pub fn new(path: impl Into<PathBuf>) -> FileProcessedEventStore {
+        FileProcessedEventStore { path: path.into() }
+    }
+
+    fn load(&self) -> Result<Vec<ProcessedEvent>, Error> {
+        match fs::read_to_string(&self.path) {
+            Ok(s) => serde_json::from_str(&s)
+                .map_err(|e| Error::internal(format!("invalid processed events: {}", e))),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(Error::internal(format!("could not read processed events: {}", e))),
+        }
+    }
+
+    fn save(&self, events: &[ProcessedEvent]) -> Result<(), Error> {
+        let json =
+            serde_json::to_string_pretty(events).map_err(|e| Error::internal(e.to_string()))?;
+        fs::write(&self.path, json)
+            .map_err(|e| Error::internal(format!("could not write processed events: {}", e)))
+    }
+
+    /// Records the state of an event, replacing its previous state.
+    fn set_state(
+        &self,
+        event_id: &str,
+        now: DateTime<Utc>,
+        state: EventState,
+    ) -> Result<(), Error> {
+        let mut events = self.load()?;
+        events.retain(|e| e.id != event_id);
+        events.push(ProcessedEvent {
+            id: event_id.to_string(),
+            processed_at: now.to_rfc3339(),
+            state,
+        });
+        self.save(&events)
+    }
+}
+
+impl ProcessedEventStore for FileProcessedEventStore {
+    fn claim(&self, event_id: &str) -> Result<Claim, Error> {
+        let now = Utc::now();
+        let events = self.load()?;
+        let claim = events
+            .iter()
+            .find(|e| e.id == event_id)
+            .map_or(Claim::Claimed, |e| e.state.claim(now));
+        if claim == Claim::Claimed {
+            let state = EventState::InProgress { lease_until: lease_until(now) };
+            self.set_state(event_id, now, state)?;
+        }
+        Ok(claim)
+    }
+
+    fn complete(&self, event_id: &str) -> Result<(), Error> {
+        self.set_state(event_id, Utc::now(), EventState::Done)
+    }
+
+    fn release(&self, event_id: &str) -> Result<(), Error> {
+        let mut events = self.load()?;
+        let len = events.len();
+        events.retain(|e| e.id != event_id);
+        if events.len() != len {
+            self.save(&events)?;
+        }
+        Ok(())
+    }
+}
+
+/// AWS credentials used to sign DynamoDB requests.
+#[derive(Clone, Debug)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Set for temporary credentials (e.g. those of the lambda role)
+    pub session_token: Option<String>,
+}
+
+/// Keeps processed events in a DynamoDB table (or a compatible service such as DynamoDB
+/// Local), whose partition key is the string attribute `id`.
+///
+/// The `status` attribute of an item is `in_progress` while a delivery handles the event,
+/// until `leaseUntil` (seconds since the epoch), and `done` once handled (items without it
+/// were written before events were claimed with a lease, and are done). Items also get an
+/// `expiresAt` attribute (seconds since the epoch), to be used as the TTL attribute of the
+/// table.
+pub struct DynamoDbProcessedEventStore {
+    client: reqwest::Client,
+    endpoint: String,
+    region: String,
+    table: String,
+    credentials: AwsCredentials,
+}
+
+/// How long processed events are kept, FastSpring doesn't redeliver events after that.
+const PROCESSED_EVENT_RETENTION_DAYS: i64 = 30;
+
+impl DynamoDbProcessedEventStore {
+    /// Creates a store for `table`. The endpoint defaults to the DynamoDB endpoint of `region`.
+    pub fn new(
+        table: &str,
+        region: &str,
+        endpoint: Option<&str>,
+        credentials: AwsCredentials,
+    ) -> DynamoDbProcessedEventStore {
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("https://dynamodb.{}.amazonaws.com", region),
+        };
+        DynamoDbProcessedEventStore {
+            client: reqwest::Client::new(),
+            endpoint,
+            region: region.to_string(),
+            table: table.to_string(),
+            credentials,
+        }
+    }
+
+    /// Calls a DynamoDB operation (e.g. `DeleteItem`) and returns the reply.
+    fn call(&self, operation: &str, body: &Value) -> Result<Value, Error> {
+        let (status, reply) = self.send(operation, body)?;
+        if !status.is_success() {
+            return Err(Error::internal(format!(
+                "DynamoDB {} replied {}: {}",
+                operation, status, reply
+            )));
+        }
+        Ok(reply)
+    }
+
+    /// Sends a DynamoDB request, and returns the status and body of the reply (which is an
+    /// error object if the call failed).
+    fn send(&self, operation: &str, body: &Value) -> Result<(StatusCode, Value), Error> {
+        let body = body.to_string();
+        let target = format!("DynamoDB_20120810.{}", operation);
+        let url = url::Url::parse(&self.endpoint)
+            .map_err(|e| Error::config(format!("invalid DynamoDB endpoint: {}", e)))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(Error::config("invalid DynamoDB endpoint")),
+        };
+
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.0".to_string()),
+            ("host", host),
+            ("x-amz-date", Utc::now().format("%Y%m%dT%H%M%SZ").to_string()),
+            ("x-amz-target", target),
+        ];
+        if let Some(ref token) = self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let authorization = sign_v4(
+            &self.credentials,
+            &self.region,
+            "dynamodb",
+            "POST",
+            &mut headers,
+            body.as_bytes(),
+        );
+
+        let mut req = self
+            .client
+            .post(&self.endpoint)
+            .header("Authorization", authorization)
+            .body(body);
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            req = req.header(name, value);
+        }
+        let mut reply = req
+            .send()
+            .map_err(|e| Error::internal(format!("DynamoDB request error: {}", e)))?;
+        let status = reply.status();
+        let reply = reply
+            .text()
+            .map_err(|e| Error::internal(format!("DynamoDB request error: {}", e)))?;
+        let reply = serde_json::from_str(&reply)
+            .map_err(|e| Error::internal(format!("invalid DynamoDB reply ({}): {}", status, e)))?;
+        Ok((status, reply))
+    }
+}
+
+/// Returns the `Authorization` header of a request to the root path of an AWS service, signed
+/// with AWS signature version 4.
+///
+/// `headers` are the headers to sign, with lowercase names. They must include `host` and
+/// `x-amz-date`, and are sorted by name.
+fn sign_v4(
+    credentials: &AwsCredentials,
+    region: &str,
+    service: &str,
+    method: &str,
+    headers: &mut [(&str, String)],
+    payload: &[u8],
+) -> String {
+    headers.sort();
+    let amz_date = headers
+        .iter()
+        .find(|(name, _)| *name == "x-amz-date")
+        .map(|(_, value)| value.as_str())
+        .unwrap_or_default();
+    let date = amz_date.get(..8).unwrap_or_default();
+
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n/\n\n{}\n{}\n{}",
+        method,
+        canonical_headers,
+        signed_headers,
+        hex::encode(hmac_sha256::Hash::hash(payload))
+    );
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(hmac_sha256::Hash::hash(canonical_request.as_bytes()))
+    );
+    let mut key = hmac_sha256::HMAC::mac(
+        date.as_bytes(),
+        format!("AWS4{}", credentials.secret_access_key).as_bytes(),
+    );
+    for part in &[region, service, "aws4_request"] {
+        key = hmac_sha256::HMAC::mac(part.as_bytes(), &key);
+    }
+    let signature = hex::encode(hmac_sha256::HMAC::mac(string_to_sign.as_bytes(), &key));
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id, scope, signed_headers, signature
+    )
+}
+
+impl ProcessedEventStore for DynamoDbProcessedEventStore {
+    fn claim(&self, event_id: &str) -> Result<Claim, Error> {
+        let now = Utc::now();
+        let expires_at = now + Duration::days(PROCESSED_EVENT_RETENTION_DAYS);
+        let request = json!({
+            "TableName": self.table,
+            "Item": {
+                "id": { "S": event_id },
+                "status": { "S": "in_progress" },
+                "leaseUntil": { "N": lease_until(now).timestamp().to_string() },
+                "processedAt": { "S": now.to_rfc3339() },
+                "expiresAt": { "N": expires_at.timestamp().to_string() }
+            },
+            // new, or claimed by a delivery whose lease expired
+            "ConditionExpression":
+                "attribute_not_exists(id) OR (#status = :in_progress AND leaseUntil <= :now)",
+            "ExpressionAttributeNames": { "#status": "status" },
+            "ExpressionAttributeValues": {
+                ":in_progress": { "S": "in_progress" },
+                ":now": { "N": now.timestamp().to_string() }
+            }
+        });
+        let (status, reply) = self.send("PutItem", &request)?;
+        if status.is_success() {
+            return Ok(Claim::Claimed);
+        }
+        let error_type = reply["__type"].as_str().unwrap_or_default();
+        if !error_type.ends_with("#ConditionalCheckFailedException") {
+            return Err(Error::internal(format!("DynamoDB PutItem replied {}: {}", status, reply)));
+        }
+
+        let reply = self.call(
+            "GetItem",
+            &json!({
+                "TableName": self.table,
+                "Key": { "id": { "S": event_id } },
+                "ConsistentRead": true
+            }),
+        )?;
+        match reply["Item"]["status"]["S"].as_str() {
+            Some("in_progress") => Ok(Claim::InProgress),
+            // or released meanwhile, in which case the next delivery handles it
+            _ if reply["Item"].is_null() => Ok(Claim::InProgress),
+            _ => Ok(Claim::Done),
+        }
+    }
+
+    fn complete(&self, event_id: &str) -> Result<(), Error> {
+        let now = Utc::now();
+        let expires_at = now + Duration::days(PROCESSED_EVENT_RETENTION_DAYS);
+        self.call(
+            "PutItem",
+            &json!({
+                "TableName": self.table,
+                "Item": {
+                    "id": { "S": event_id },
+                    "status": { "S": "done" },
+                    "processedAt": { "S": now.to_rfc3339() },
+                    "expiresAt": { "N": expires_at.timestamp().to_string() }
+                }
+            }),
+        )?;
+        Ok(())
+    }
+
+    fn release(&self, event_id: &str) -> Result<(), Error> {
+        self.call(
+            "DeleteItem",
+            &json!({
+                "TableName": self.table,
+                "Key": { "id": { "S": event_id } }
+            }),
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_lease_is_taken_over() {
+        let now = Utc::now();
+        let state = EventState::InProgress { lease_until: now };
+        assert_eq!(state.claim(now - Duration::seconds(1)), Claim::InProgress);
+        assert_eq!(state.claim(now), Claim::Claimed);
+        assert_eq!(EventState::Done.claim(now), Claim::Done);
+    }
+
+    #[test]
+    fn events_are_done_once_completed() {
+        let store = MemoryProcessedEventStore::new();
+        assert_eq!(store.claim("a").unwrap(), Claim::Claimed);
+        assert_eq!(store.claim("a").unwrap(), Claim::InProgress);
+        store.complete("a").unwrap();
+        assert_eq!(store.claim("a").unwrap(), Claim::Done);
+
+        assert_eq!(store.claim("b").unwrap(), Claim::Claimed);
+        store.release("b").unwrap();
+        assert_eq!(store.claim("b").unwrap(), Claim::Claimed);
+    }
+
+    #[test]
+    fn events_recorded_without_state_are_done() {
+        let event: ProcessedEvent =
+            serde_json::from_str(r#"{"id": "a", "processed_at": "2020-01-01T00:00:00+00:00"}"#)
+                .unwrap();
+        assert_eq!(event.state, EventState::Done);
+    }
+
+    // from the AWS signature version 4 test suite
+    fn credentials() -> AwsCredentials {
+        AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        }
+    }
+
+    fn headers() -> Vec<(&'static str, String)> {
+        vec![
+            ("x-amz-date", "20150830T123600Z".to_string()),
+            ("host", "example.amazonaws.com".to_string()),
+        ]
+    }
+
+    #[test]
+    fn get_vanilla_is_signed() {
+        let authorization =
+            sign_v4(&credentials(), "us-east-1", "service", "GET", &mut headers(), b"");
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn post_vanilla_is_signed() {
+        let authorization =
+            sign_v4(&credentials(), "us-east-1", "service", "POST", &mut headers(), b"");
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b"
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//! Protection against replayed webhook requests.
+//!
+//! A signed request that was captured could otherwise be sent again as is, e.g. to generate
+//! licenses again. Requests are remembered by their key (see `request_key`), and a request seen
+//! before gets the reply recorded for it, or is rejected while the first one is being handled.
+//! Requests that fail are forgotten, so that the sender can retry them.
+//!
+//! Seen requests are kept in memory, i.e. per lambda instance.
+use crate::error::Error;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Key of a request in the cache.
+pub type RequestKey = [u8; 32];
+
+/// Returns the key of a request: the hash of its path, of the header telling what triggered it
+/// (if any) and of its body, which the signature covers.
+pub fn request_key(path: &str, trigger: Option<&[u8]>, body: &[u8]) -> RequestKey {
+    let mut hash = hmac_sha256::Hash::new();
+    hash.update(path.as_bytes());
+    hash.update(b"\n");
+    hash.update(trigger.unwrap_or_default());
+    hash.update(b"\n");
+    hash.update(body);
+    hash.finalize()
+}
+
+struct SeenRequest {
+    at: DateTime<Utc>,
+    /// Reply to send again if the request is received again
+    reply: Option<String>,
+}
+
+pub struct ReplayGuard {
+    /// Maximum age of a request, if checked
+    max_age: Option<Duration>,
+    /// How long requests are remembered, zero to disable the cache
+    retention: Duration,
+    seen: Mutex<HashMap<RequestKey, SeenRequest>>,
+}
+
+impl ReplayGuard {
+    pub fn new(max_age: Option<Duration>, retention: Duration) -> ReplayGuard {
+        ReplayGuard {
+            max_age,
+            retention,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Rejects a request sent at `sent_at` if it is older than the freshness window.
+    pub fn check_fresh(&self, sent_at: DateTime<Utc>) -> Result<(), Error> {
+        match self.max_age {
+            Some(max_age) if Utc::now() - sent_at > max_age => Err(Error::Replayed(format!(
+                "request sent at {} is older than {}s",
+                sent_at,
+                max_age.num_seconds()
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records a request. If the same request was seen before, returns the reply recorded for
+    /// it, or rejects it if there is none.
+    pub fn claim(&self, key: &RequestKey) -> Result<Option<String>, Error> {
+        if self.retention <= Duration::zero() {
+            return Ok(None);
+        }
+        let now = Utc::now();
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, request| now - request.at < self.retention);
+        if let Some(request) = seen.get(key) {
+            return match request.reply {
+                Some(ref reply) => Ok(Some(reply.clone())),
+                None => Err(Error::Replayed(format!(
+                    "same request already received at {}",
+                    request.at
+                ))),
+            };
+        }
+        seen.insert(*key, SeenRequest { at: now, reply: None });
+        Ok(None)
+    }
+
+    /// Records the reply to a request, sent again when the request is received again.
+    pub fn record_reply(&self, key: &RequestKey, reply: &str) {
+        if let Some(request) = self.seen.lock().unwrap().get_mut(key) {
+            request.reply = Some(reply.to_string());
+        }
+    }
+
+    /// Forgets a request, so that it is accepted again.
+    pub fn release(&self, key: &RequestKey) {
+        self.seen.lock().unwrap().remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard() -> ReplayGuard {
+        ReplayGuard::new(Some(Duration::minutes(5)), Duration::hours(1))
+    }
+
+    #[test]
+    fn repeated_requests_are_rejected_until_released() {
+        let guard = guard();
+        let key = request_key("/path", None, b"body");
+        assert_eq!(guard.claim(&key).unwrap(), None);
+        assert!(matches!(guard.claim(&key), Err(Error::Replayed(_))));
+        guard.release(&key);
+        assert_eq!(guard.claim(&key).unwrap(), None);
+    }
+
+    #[test]
+    fn repeated_requests_get_the_recorded_reply() {
+        let guard = guard();
+        let key = request_key("/path", None, b"body");
+        assert_eq!(guard.claim(&key).unwrap(), None);
+        guard.record_reply(&key, "");
+        assert_eq!(guard.claim(&key).unwrap(), Some(String::new()));
+        assert_eq!(guard.claim(&key).unwrap(), Some(String::new()));
+    }
+
+    #[test]
+    fn requests_are_forgotten_after_the_retention() {
+        let guard = guard();
+        let key = request_key("/path", None, b"body");
+        assert_eq!(guard.claim(&key).unwrap(), None);
+        guard.seen.lock().unwrap().get_mut(&key).unwrap().at -= Duration::hours(1);
+        assert_eq!(guard.claim(&key).unwrap(), None);
+        assert!(guard.claim(&key).is_err());
+
+        let guard = ReplayGuard::new(None, Duration::zero());
+        assert_eq!(guard.claim(&key).unwrap(), None);
+        assert_eq!(guard.claim(&key).unwrap(), None);
+    }
+
+    #[test]
+    fn keys_depend_on_path_trigger_and_body() {
+        let key = request_key("/path", Some(b"members:create"), b"body");
+        assert_eq!(key, request_key("/path", Some(b"members:create"), b"body"));
+        assert_ne!(key, request_key("/other", Some(b"members:create"), b"body"));
+        assert_ne!(key, request_key("/path", Some(b"members:pledge:create"), b"body"));
+        assert_ne!(key, request_key("/path", None, b"body"));
+        assert_ne!(key, request_key("/path", Some(b"members:create"), b"other"));
+    }
+
+    #[test]
+    fn old_requests_are_rejected() {
+        let guard = guard();
+        assert!(guard.check_fresh(Utc::now() - Duration::minutes(1)).is_ok());
+        assert!(matches!(
+            guard.check_fresh(Utc::now() - Duration::minutes(10)),
+            Err(Error::Replayed(_))
+        ));
+        let guard = ReplayGuard::new(None, Duration::hours(1));
+        assert!(guard.check_fresh(Utc::now() - Duration::days(10)).is_ok());
+    }
+}
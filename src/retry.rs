@@ -0,0 +1,182 @@
+//! Retries of outbound API calls.
+use chrono::{DateTime, Utc};
+use http::header::{HeaderMap, RETRY_AFTER};
+use log::warn;
+use rand::Rng;
+use std::fmt::Display;
+use std::thread;
+use std::time::Duration;
+
+/// Error of an outbound call that may succeed if retried.
+pub trait Transient {
+    /// Returns whether retrying the call may succeed (network error, rate limit or server error).
+    fn is_transient(&self) -> bool;
+
+    /// Returns the delay requested by the server before retrying, if any.
+    fn retry_after(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Exponential backoff with full jitter.
+#[derive(Copy, Clone, Debug)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Upper bound of the delay before the first retry, doubled on each retry
+    pub base_delay: Duration,
+    /// Upper bound of any delay. If the server asks to wait longer, the call is not retried.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Returns the delay before the given retry (starting at 1), or `None` to give up.
+    pub fn delay(&self, retry: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        if retry >= self.max_attempts {
+            return None;
+        }
+        if let Some(retry_after) = retry_after {
+            return if retry_after <= self.max_delay {
+                Some(retry_after)
+            } else {
+                None
+            };
+        }
+        let cap = self
+            .base_delay
+            .checked_mul(1 << (retry - 1).min(16))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        let cap_ms = cap.as_millis() as u64;
+        let ms = if cap_ms == 0 {
+            0
+        } else {
+            rand::thread_rng().gen_range(0, cap_ms + 1)
+        };
+        Some(Duration::from_millis(ms))
+    }
+
+    /// Calls `f` until it succeeds, fails with a non-transient error, or attempts run out.
+    pub fn run<T, E, F>(&self, what: &str, mut f: F) -> Result<T, E>
+    where
+        E: Transient + Display,
+        F: FnMut() -> Result<T, E>,
+    {
+        let mut retry = 0;
+        loop {
+            let e = match f() {
+                Ok(v) => return Ok(v),
+                Err(e) => e,
+            };
+            retry += 1;
+            let delay = if e.is_transient() {
+                self.delay(retry, e.retry_after())
+            } else {
+                None
+            };
+            match delay {
+                Some(delay) => {
+                    warn!(
+                        "{} failed (attempt {}/{}), retrying in {:?}: {}",
+                        what, retry, self.max_attempts, delay, e
+                    );
+                    thread::sleep(delay);
+                }
+                None => return Err(e),
+            }
+        }
+    }
+}
+
+/// Parses the `Retry-After` header of a reply (either a number of seconds or an HTTP date).
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now())
+        .to_std()
+        .ok()
+        .or(Some(Duration::from_secs(0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::HeaderValue;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+        }
+    }
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap());
+        headers
+    }
+
+    #[test]
+    fn delays_are_capped() {
+        let policy = policy();
+        for _ in 0..100 {
+            assert!(policy.delay(1, None).unwrap() <= Duration::from_millis(100));
+            assert!(policy.delay(2, None).unwrap() <= Duration::from_millis(200));
+            // 400ms capped by max_delay
+            assert!(policy.delay(3, None).unwrap() <= Duration::from_millis(300));
+        }
+        assert_eq!(policy.delay(4, None), None);
+        assert_eq!(RetryPolicy::none().delay(1, None), None);
+    }
+
+    #[test]
+    fn retry_after_is_used_up_to_max_delay() {
+        let policy = policy();
+        let delay = Duration::from_millis(250);
+        assert_eq!(policy.delay(1, Some(delay)), Some(delay));
+        let max = Duration::from_millis(300);
+        assert_eq!(policy.delay(3, Some(max)), Some(max));
+        // the server asks to wait too long, giving up
+        assert_eq!(policy.delay(1, Some(Duration::from_millis(301))), None);
+        assert_eq!(policy.delay(4, Some(delay)), None);
+    }
+
+    #[test]
+    fn retry_after_is_parsed_as_seconds_or_date() {
+        assert_eq!(retry_after(&headers("120")), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(&headers(" 5 ")), Some(Duration::from_secs(5)));
+        // dates in the past mean now
+        assert_eq!(
+            retry_after(&headers("Wed, 21 Oct 2015 07:28:00 GMT")),
+            Some(Duration::from_secs(0))
+        );
+        let in_an_hour = (Utc::now() + chrono::Duration::hours(1)).to_rfc2822();
+        let delay = retry_after(&headers(&in_an_hour)).unwrap();
+        assert!(delay > Duration::from_secs(3590) && delay <= Duration::from_secs(3600));
+        assert_eq!(retry_after(&headers("soon")), None);
+        assert_eq!(retry_after(&headers("-1")), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+}
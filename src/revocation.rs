@@ -0,0 +1,242 @@
+//! Pending license revocations.
+//!
+//! Licenses of deactivated subscriptions are first suspended, and only revoked once their
+//! grace period has passed without the subscription being reactivated.
+use crate::error::Error;
+use crate::keygen::{KeygenClient, KeygenError};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// License metadata key holding the revocation deadline (used by `KeygenRevocationStore`).
+const REVOKE_AFTER_METADATA_KEY: &str = "revokeAfter";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingRevocation {
+    pub license_key: String,
+    pub subscription_id: String,
+    pub revoke_after: DateTime<Utc>,
+}
+
+/// Storage for pending revocations.
+pub trait RevocationStore {
+    /// Records a pending revocation, replacing any existing one for the same license.
+    fn add(&self, revocation: &PendingRevocation) -> Result<(), Error>;
+    /// Removes the pending revocation of a license, if any.
+    fn remove(&self, license_key: &str) -> Result<(), Error>;
+    /// Returns whether a revocation is pending for a license.
+    fn is_pending(&self, license_key: &str) -> Result<bool, Error>;
+    /// Returns the pending revocations whose deadline has passed.
+    fn due(&self, now: DateTime<Utc>) -> Result<Vec<PendingRevocation>, Error>;
+}
+
+/// Stores pending revocations in a local JSON file. Intended for testing.
+pub struct FileRevocationStore {
+    path: PathBuf,
+}
+
+impl FileRevocationStore {
+    pub fn new(path: impl Into<PathBuf>) -> FileRevocationStore {
+        FileRevocationStore { path: path.into() }
+    }
+
+    fn load(&self) -> Result<Vec<PendingRevocation>, Error> {
+        match fs::read_to_string(&self.path) {
+            Ok(s) => serde_json::from_str(&s)
+                .map_err(|e| Error::internal(format!("invalid pending revocations: {}", e))),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(Error::internal(format!("could not read pending revocations: {}", e))),
+        }
+    }
+
+    fn save(&self, revocations: &[PendingRevocation]) -> Result<(), Error> {
+        let json = serde_json::to_string_pretty(revocations)
+            .map_err(|e| Error::internal(e.to_string()))?;
+        fs::write(&self.path, json)
+            .map_err(|e| Error::internal(format!("could not write pending revocations: {}", e)))
+    }
+}
+
+impl RevocationStore for FileRevocationStore {
+    fn add(&self, revocation: &PendingRevocation) -> Result<(), Error> {
+        let mut revocations = self.load()?;
+        revocations.retain(|r| r.license_key != revocation.license_key);
+        revocations.push(revocation.clone());
+        self.save(&revocations)
+    }
+
+    fn remove(&self, license_key: &str) -> Result<(), Error> {
+        let mut revocations = self.load()?;
+        let len = revocations.len();
+        revocations.retain(|r| r.license_key != license_key);
+        if revocations.len() != len {
+            self.save(&revocations)?;
+        }
+        Ok(())
+    }
+
+    fn is_pending(&self, license_key: &str) -> Result<bool, Error> {
+        Ok(self.load()?.iter().any(|r| r.license_key == license_key))
+    }
+
+    fn due(&self, now: DateTime<Utc>) -> Result<Vec<PendingRevocation>, Error> {
+        Ok(self
+            .load()?
+            .into_iter()
+            .filter(|r| r.revoke_after <= now)
+            .collect())
+    }
+}
+
+/// Stores the revocation deadline in the metadata of the (suspended) license itself.
+pub struct KeygenRevocationStore {
+    keygen: KeygenClient,
+}
+
+impl KeygenRevocationStore {
+    pub fn new(keygen: KeygenClient) -> KeygenRevocationStore {
+        KeygenRevocationStore { keygen }
+    }
+}
+
+impl RevocationStore for KeygenRevocationStore {
+    fn add(&self, revocation: &PendingRevocation) -> Result<(), Error> {
+        let mut metadata = self.keygen.get_license_metadata(&revocation.license_key)?;
+        metadata.insert(
+            REVOKE_AFTER_METADATA_KEY.to_string(),
+            revocation.revoke_after.to_rfc3339().into(),
+        );
+        self.keygen
+            .set_license_metadata(&revocation.license_key, &metadata)?;
+        Ok(())
+    }
+
+    fn remove(&self, license_key: &str) -> Result<(), Error> {
+        let mut metadata = match self.keygen.get_license_metadata(license_key) {
+            Ok(metadata) => metadata,
+            // the license has already been revoked
+            Err(ref e) if e.is_not_found() => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        if metadata.remove(REVOKE_AFTER_METADATA_KEY).is_some() {
+            self.keygen.set_license_metadata(license_key, &metadata)?;
+        }
+        Ok(())
+    }
+
+    fn is_pending(&self, license_key: &str) -> Result<bool, Error> {
+        match self.keygen.get_license_metadata(license_key) {
+            Ok(metadata) => Ok(metadata.contains_key(REVOKE_AFTER_METADATA_KEY)),
+            Err(ref e) if e.is_not_found() => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn due(&self, now: DateTime<Utc>) -> Result<Vec<PendingRevocation>, Error> {
+        let mut revocations = Vec::new();
+        for license in self.keygen.list_suspended_licenses()?.iter() {
+            let attributes = &license["attributes"];
+            let revoke_after = match attributes["metadata"][REVOKE_AFTER_METADATA_KEY]
+                .as_str()
+                .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+            {
+                Some(date) => date.with_timezone(&Utc),
+                None => continue,
+            };
+            if revoke_after > now {
+                continue;
+            }
+            revocations.push(PendingRevocation {
+                license_key: attributes["key"]
+                    .as_str()
+                    .ok_or_else(|| KeygenError::InvalidReply("missing license key".to_string()))?
+                    .to_string(),
+                subscription_id: attributes["metadata"]["fastSpringSubscriptionId"]
+                    .as_str()
+                    .unwrap_or("")
+                    .to_string(),
+                revoke_after,
+            });
+        }
+        Ok(revocations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// A store in a file of its own, removed when dropped.
+    struct TempStore(FileRevocationStore);
+
+    impl TempStore {
+        fn new(name: &str) -> TempStore {
+            let path = std::env::temp_dir()
+                .join(format!("pending-revocations-{}-{}.json", name, std::process::id()));
+            let _ = fs::remove_file(&path);
+            TempStore(FileRevocationStore::new(path))
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0.path);
+        }
+    }
+
+    fn revocation(key: &str, day: u32) -> PendingRevocation {
+        PendingRevocation {
+            license_key: key.to_string(),
+            subscription_id: "sub".to_string(),
+            revoke_after: Utc.with_ymd_and_hms(2024, 7, day, 0, 0, 0).unwrap(),
+        }
+    }
+
+    fn keys(revocations: &[PendingRevocation]) -> Vec<&str> {
+        revocations.iter().map(|r| r.license_key.as_str()).collect()
+    }
+
+    #[test]
+    fn missing_file_has_no_revocations() {
+        let store = TempStore::new("missing");
+        assert!(store.0.due(Utc::now()).unwrap().is_empty());
+        assert!(!store.0.is_pending("a").unwrap());
+        store.0.remove("a").unwrap();
+    }
+
+    #[test]
+    fn revocations_are_due_after_their_deadline() {
+        let store = TempStore::new("due");
+        store.0.add(&revocation("a", 1)).unwrap();
+        store.0.add(&revocation("b", 10)).unwrap();
+        let day = |d| Utc.with_ymd_and_hms(2024, 7, d, 0, 0, 0).unwrap();
+        assert!(store.0.due(day(1) - chrono::Duration::seconds(1)).unwrap().is_empty());
+        assert_eq!(keys(&store.0.due(day(1)).unwrap()), vec!["a"]);
+        assert_eq!(keys(&store.0.due(day(20)).unwrap()), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn adding_replaces_the_revocation_of_the_license() {
+        let store = TempStore::new("replace");
+        store.0.add(&revocation("a", 1)).unwrap();
+        store.0.add(&revocation("a", 10)).unwrap();
+        let due = store.0.due(Utc::now()).unwrap();
+        assert_eq!(keys(&due), vec!["a"]);
+        assert_eq!(due[0].revoke_after, revocation("a", 10).revoke_after);
+    }
+
+    #[test]
+    fn removed_revocations_are_no_longer_pending() {
+        let store = TempStore::new("remove");
+        store.0.add(&revocation("a", 1)).unwrap();
+        store.0.add(&revocation("b", 1)).unwrap();
+        assert!(store.0.is_pending("a").unwrap());
+        store.0.remove("a").unwrap();
+        assert!(!store.0.is_pending("a").unwrap());
+        assert!(store.0.is_pending("b").unwrap());
+        assert_eq!(keys(&store.0.due(Utc::now()).unwrap()), vec!["b"]);
+    }
+}
@@ -0,0 +1,167 @@
+//! Webhook secrets, several of which may be accepted at once while one is being rotated.
+use chrono::{DateTime, NaiveDate, Utc};
+use std::fmt;
+use std::str::FromStr;
+
+/// A secret, accepted until it expires.
+#[derive(Clone)]
+pub struct Secret {
+    pub value: String,
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl Secret {
+    /// Short identifier of the secret for the logs, which doesn't reveal it.
+    pub fn fingerprint(&self) -> String {
+        hex::encode(&hmac_sha256::Hash::hash(self.value.as_bytes())[..4])
+    }
+
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.expires.is_none_or(|expires| now < expires)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "secret {}", self.fingerprint())?;
+        if let Some(expires) = self.expires {
+            write!(f, " (expires {})", expires)?;
+        }
+        Ok(())
+    }
+}
+
+// never print the secret itself
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+/// The secrets accepted for a signature.
+///
+/// Parsed from a comma-separated list of `<secret>[@<expiry>]`, where the expiry is an RFC 3339
+/// date and time or a date (expiring at the start of that day, UTC), e.g.
+/// `new-secret,old-secret@2024-07-01`.
+#[derive(Clone, Debug, Default)]
+pub struct Secrets(Vec<Secret>);
+
+impl Secrets {
+    /// Returns the secrets that haven't expired yet.
+    pub fn active(&self) -> impl Iterator<Item = &Secret> {
+        let now = Utc::now();
+        self.0.iter().filter(move |s| s.is_active(now))
+    }
+
+    /// Returns the first active secret for which `matches` is true.
+    pub fn find(&self, mut matches: impl FnMut(&str) -> bool) -> Option<&Secret> {
+        self.active().find(|s| matches(&s.value))
+    }
+}
+
+/// Parses an expiry, or returns `None` if `s` is not one.
+fn parse_expiry(s: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(s) {
+        return Some(date.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+    Some(DateTime::from_naive_utc_and_offset(date.and_hms_opt(0, 0, 0)?, Utc))
+}
+
+impl FromStr for Secrets {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let secrets: Vec<_> = s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                // secrets may contain '@', so only a valid expiry is split off
+                let (value, expires) = entry
+                    .rfind('@')
+                    .and_then(|i| Some((&entry[..i], Some(parse_expiry(&entry[i + 1..])?))))
+                    .unwrap_or((entry, None));
+                Secret {
+                    value: value.to_string(),
+                    expires,
+                }
+            })
+            .collect();
+        if secrets.iter().any(|s| s.value.is_empty()) {
+            return Err("empty secret".to_string());
+        }
+        if secrets.is_empty() {
+            return Err("no secret".to_string());
+        }
+        Ok(Secrets(secrets))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Vec<(String, Option<DateTime<Utc>>)> {
+        let secrets: Secrets = s.parse().unwrap();
+        secrets.0.into_iter().map(|s| (s.value, s.expires)).collect()
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn at_signs_are_kept_in_secrets() {
+        assert_eq!(parse("p@ss"), vec![("p@ss".to_string(), None)]);
+        assert_eq!(parse("a@b@c"), vec![("a@b@c".to_string(), None)]);
+        assert_eq!(
+            parse("p@ss@2024-07-01"),
+            vec![("p@ss".to_string(), Some(utc("2024-07-01T00:00:00Z")))]
+        );
+        // not an expiry
+        assert_eq!(parse("secret@2024-13-01"), vec![("secret@2024-13-01".to_string(), None)]);
+    }
+
+    #[test]
+    fn expiries_are_dates_or_rfc3339() {
+        assert_eq!(
+            parse("old@2024-07-01,older@2024-06-01T12:30:00+02:00"),
+            vec![
+                ("old".to_string(), Some(utc("2024-07-01T00:00:00Z"))),
+                ("older".to_string(), Some(utc("2024-06-01T10:30:00Z"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_entries_are_skipped() {
+        assert_eq!(
+            parse(" new , ,old@2024-07-01,"),
+            vec![
+                ("new".to_string(), None),
+                ("old".to_string(), Some(utc("2024-07-01T00:00:00Z"))),
+            ]
+        );
+        assert!("".parse::<Secrets>().is_err());
+        assert!(" , ".parse::<Secrets>().is_err());
+        assert!("@2024-07-01".parse::<Secrets>().is_err());
+        assert!("new,@2024-07-01".parse::<Secrets>().is_err());
+    }
+
+    #[test]
+    fn expired_secrets_are_not_active() {
+        let secret = Secret {
+            value: "old".to_string(),
+            expires: Some(utc("2024-07-01T00:00:00Z")),
+        };
+        assert!(secret.is_active(utc("2024-06-30T23:59:59Z")));
+        assert!(!secret.is_active(utc("2024-07-01T00:00:00Z")));
+
+        let secrets: Secrets = "new,old@2000-01-01,next@2999-01-01".parse().unwrap();
+        let active: Vec<_> = secrets.active().map(|s| s.value.as_str()).collect();
+        assert_eq!(active, vec!["new", "next"]);
+        assert!(secrets.find(|s| s == "old").is_none());
+        assert_eq!(secrets.find(|s| s == "next").unwrap().value, "next");
+    }
+}
@@ -0,0 +1,1282 @@
+//! Request handlers, shared by the lambda and the standalone server.
+use crate::config::Config;
+use crate::error::Error;
+use crate::fastspring;
+use crate::fastspring::{FastSpringClient, FastSpringError};
+use crate::fastspring::events::{
+    Charge, Event, Events, Item, Order, RawEvent, Return, ReturnItem, Subscription,
+    SubscriptionEntry,
+};
+use crate::keygen::{self, DeactivationAction, KeygenClient};
+use crate::util;
+use crate::patreon;
+use crate::patreon::members::{MemberEvent, PatronStatus};
+use crate::processed_events::{Claim, ProcessedEventStore};
+use crate::replay::{self, ReplayGuard, RequestKey};
+use crate::revocation::{
+    FileRevocationStore, KeygenRevocationStore, PendingRevocation, RevocationStore,
+};
+use chrono::{DateTime, Datelike, Duration, TimeZone, Utc};
+use http::header::CONTENT_TYPE;
+use lambda_http::{Body, Request, RequestExt, Response};
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+
+const KEYGEN_CREATE_PATH: &str = "/fastspring-keygen-integration-service/keygen/create";
+const PATREON_PATH: &str = "/fastspring-keygen-integration-service/patreon";
+
+/// License metadata key holding the number of consecutive failed subscription charges.
+const FAILED_CHARGES_METADATA_KEY: &str = "failedCharges";
+
+/// License metadata key holding the ID of the last `subscription.charge.failed` event counted in
+/// `failedCharges`.
+const LAST_FAILED_CHARGE_METADATA_KEY: &str = "lastFailedCharge";
+
+/// License metadata key holding the expiry of a license (`null` if none) before it was set to
+/// expire by the `Expire` deactivation action.
+const EXPIRY_BEFORE_DEACTIVATION_METADATA_KEY: &str = "expiryBeforeDeactivation";
+
+/// State kept across requests (e.g. across the invocations of a lambda instance).
+///
+/// The API clients are created once, so that their connections are reused across requests.
+pub struct State {
+    pub fastspring: FastSpringClient,
+    pub keygen: KeygenClient,
+    pub revocations: Box<dyn RevocationStore + Send + Sync>,
+    pub processed_events: Box<dyn ProcessedEventStore + Send + Sync>,
+    pub replay: ReplayGuard,
+}
+
+impl State {
+    pub fn new(config: &Config) -> State {
+        let keygen = config.keygen.client();
+        State {
+            fastspring: config.fastspring.client(),
+            revocations: revocation_store(config, &keygen),
+            keygen,
+            processed_events: config.processed_events.store(),
+            replay: config.replay.guard(),
+        }
+    }
+}
+
+/// Handles a request, replying with an error response if it fails.
+pub fn router(config: &Config, state: &State, req: Request) -> Response<Body> {
+    debug!("router request={:?}", req);
+    debug!("path={:?}", req.uri().path());
+    debug!("query={:?}", req.query_string_parameters());
+
+    match route(config, state, req) {
+        Ok(response) => response,
+        Err(e) => {
+            error!("{} ({})", e, e.kind());
+            error_response(&e)
+        }
+    }
+}
+
+/// Returns the response to a failed request, telling the sender whether to retry.
+fn error_response(e: &Error) -> Response<Body> {
+    Response::builder()
+        .status(e.status())
+        .header(CONTENT_TYPE, "application/json")
+        .body(e.body().to_string().into())
+        .unwrap()
+}
+
+fn route(config: &Config, state: &State, req: Request) -> Result<Response<Body>, Error> {
+    let fastspring = &state.fastspring;
+    let keygen = &state.keygen;
+    let revocations = &*state.revocations;
+
+    let replay_key = replay_key(&req);
+    if let Some(ref key) = replay_key {
+        // checked first, so that unsigned requests don't make it into the cache
+        verify_signature(config, &req)?;
+        if let Some(reply) = state.replay.claim(key)? {
+            info!("request already handled, sending the same reply");
+            return Ok(text_response(reply));
+        }
+    }
+    let result = match req.uri().path() {
+        KEYGEN_CREATE_PATH => match *req.method() {
+            http::Method::POST => handle_keygen_create(keygen, req),
+            _ => not_allowed(req),
+        },
+        "/fastspring-keygen-integration-service/webhooks" => match *req.method() {
+            http::Method::POST => handle_webhook(config, fastspring, keygen, revocations, state, req),
+            _ => not_allowed(req),
+        },
+        PATREON_PATH => match *req.method() {
+            http::Method::POST => handle_patreon_webhook(config, keygen, revocations, req),
+            _ => not_allowed(req),
+        },
+        _ => not_found(req),
+    };
+    if let Some(ref key) = replay_key {
+        match result {
+            // retries of the license generator get the licenses issued the first time, Patreon
+            // retries an empty reply
+            Ok(ref response) => {
+                if let Ok(reply) = util::body_str(response.body()) {
+                    state.replay.record_reply(key, reply);
+                }
+            }
+            // requests that fail are forgotten, so that they can be retried
+            Err(_) => state.replay.release(key),
+        }
+    }
+    result
+}
+
+/// Returns the key of a request in the replay cache, `None` if it is not cached.
+///
+/// FastSpring webhooks are not cached: FastSpring delivers the same body again when the reply
+/// is lost, and their events are deduplicated by ID instead (see `process_event`).
+fn replay_key(req: &Request) -> Option<RequestKey> {
+    let path = req.uri().path();
+    if *req.method() != http::Method::POST || (path != KEYGEN_CREATE_PATH && path != PATREON_PATH)
+    {
+        return None;
+    }
+    // Patreon sends the same body for several events, e.g. `members:create` and
+    // `members:pledge:create`
+    let trigger = req.headers().get("X-Patreon-Event").map(|h| h.as_bytes());
+    Some(replay::request_key(path, trigger, util::body_bytes(req.body())))
+}
+
+/// Checks the signature of a request cached by `replay_key`.
+fn verify_signature(config: &Config, req: &Request) -> Result<(), Error> {
+    let verified = match req.uri().path() {
+        KEYGEN_CREATE_PATH => {
+            fastspring::verify_license_gen(req, &config.fastspring.license_gen_private_key)
+        }
+        PATREON_PATH => patreon::authentify_web_hook(req, &config.patreon.webhook_secret),
+        _ => false,
+    };
+    if verified {
+        Ok(())
+    } else {
+        Err(Error::InvalidSignature)
+    }
+}
+
+fn text_response(body: String) -> Response<Body> {
+    Response::builder()
+        .status(http::StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(body.into())
+        .unwrap()
+}
+
+/// Returns the store for pending revocations.
+///
+/// Pending revocations are kept in the license metadata, unless `PENDING_REVOCATIONS_FILE` is set.
+fn revocation_store(
+    config: &Config,
+    keygen: &KeygenClient,
+) -> Box<dyn RevocationStore + Send + Sync> {
+    match config.pending_revocations_file {
+        Some(ref path) => Box::new(FileRevocationStore::new(path)),
+        None => Box::new(KeygenRevocationStore::new(keygen.clone())),
+    }
+}
+
+/// Returns the license key part of an activation code (`<activation token>.<license key>`).
+fn license_key(code: &str) -> Result<&str, Error> {
+    code.split('.')
+        .nth(1)
+        .ok_or_else(|| Error::malformed("invalid license key"))
+}
+
+/// Handles Patreon webhooks, whose signature was checked by `route`.
+fn handle_patreon_webhook(
+    config: &Config,
+    keygen: &KeygenClient,
+    revocations: &dyn RevocationStore,
+    req: Request,
+) -> Result<Response<Body>, Error>
+{
+    let trigger = req.headers().get("X-Patreon-Event")
+        .ok_or_else(|| Error::malformed("invalid format (X-Patreon-Event)"))?
+        .to_str().ok().ok_or_else(|| Error::malformed("invalid format (X-Patreon-Event)"))?;
+
+    debug!("X-Patreon-Event: {}", trigger);
+    let body = util::body_to_json(req.body())?;
+
+    match trigger {
+        "pledges:create" => {
+            patreon_handle_pledge_create(config, keygen, revocations, &body)?;
+        }
+        "pledges:update" => {
+            patreon_handle_pledge_update(config, keygen, revocations, &body)?;
+        }
+        "pledges:delete" => {
+            patreon_handle_pledge_delete(config, keygen, revocations, &body)?;
+        }
+        // Patreon sends `members:create` along with `members:pledge:create` (with the same
+        // body), only the latter issues a license
+        "members:pledge:create" => {
+            let event = MemberEvent::parse(trigger, body)?;
+            patreon_handle_member_create(config, keygen, revocations, &event)?;
+        }
+        "members:create" | "members:update" | "members:pledge:update" => {
+            let event = MemberEvent::parse(trigger, body)?;
+            patreon_handle_member_update(config, keygen, revocations, &event)?;
+        }
+        "members:delete" | "members:pledge:delete" => {
+            let event = MemberEvent::parse(trigger, body)?;
+            patreon_handle_member_delete(config, keygen, revocations, &event)?;
+        }
+        _ => {
+            warn!("unhandled Patreon trigger: {}", trigger);
+        }
+    }
+
+    Ok(Response::builder()
+        .status(http::StatusCode::OK)
+        .body(Body::default())
+        .unwrap())
+}
+
+/// Patreon pledge create trigger
+fn patreon_handle_pledge_create(
+    config: &Config,
+    keygen: &KeygenClient,
+    revocations: &dyn RevocationStore,
+    body: &serde_json::Value,
+) -> Result<Response<Body>, Error>
+{
+    debug!("handle_pledge_create {:?}", body);
+
+    let user_id = body["data"]["relationships"]["patron"]["data"]["id"].as_str().ok_or_else(|| Error::malformed("invalid format (.data.relationships.patron.data.id)"))?;
+
+    let mut user_email = None;
+    for included in body["included"].as_array().ok_or_else(|| Error::malformed("invalid format (.included)"))?.iter() {
+        if included["id"].as_str().ok_or_else(|| Error::malformed("invalid format (.included.#.id)"))? == user_id {
+            user_email = Some(included["attributes"]["email"].as_str().ok_or_else(|| Error::malformed("invalid format (.included.#.attributes.email)"))?);
+        }
+    }
+
+    let user_email = user_email.ok_or_else(|| Error::malformed("could not find patron email"))?;
+
+    issue_patron_license(config, keygen, revocations, user_id, user_email, pledge_policy(config, body))?;
+
+    Ok(Response::builder()
+        .status(http::StatusCode::OK)
+        .body(().into())
+        .unwrap())
+}
+
+/// Patreon pledge update trigger
+///
+/// Moves the licenses of the patron to the policy of their new tier, keeping the license keys.
+fn patreon_handle_pledge_update(
+    config: &Config,
+    keygen: &KeygenClient,
+    revocations: &dyn RevocationStore,
+    body: &serde_json::Value,
+) -> Result<Response<Body>, Error>
+{
+    debug!("handle_pledge_update {:?}", body);
+
+    let user_id = body["data"]["relationships"]["patron"]["data"]["id"].as_str().ok_or_else(|| Error::malformed("invalid format (.data.relationships.patron.data.id)"))?;
+
+    if move_patron_licenses(keygen, user_id, pledge_policy(config, body))? == 0 {
+        warn!("no license found for patron {}, creating one", user_id);
+        return patreon_handle_pledge_create(config, keygen, revocations, body);
+    }
+
+    Ok(Response::builder()
+        .status(http::StatusCode::OK)
+        .body(().into())
+        .unwrap())
+}
+
+/// Returns the keygen policy for the tier or amount of a pledge.
+fn pledge_policy<'a>(config: &'a Config, body: &serde_json::Value) -> &'a str {
+    let tier_id = body["data"]["relationships"]["reward"]["data"]["id"].as_str();
+    let amount_cents = body["data"]["attributes"]["amount_cents"].as_u64().unwrap_or(0) as u32;
+    config
+        .patreon
+        .tier_policies
+        .policy(tier_id.as_slice(), amount_cents)
+        .unwrap_or(&config.patreon.community_policy_id)
+}
+
+/// Patreon pledge delete trigger
+fn patreon_handle_pledge_delete(
+    config: &Config,
+    keygen: &KeygenClient,
+    revocations: &dyn RevocationStore,
+    body: &serde_json::Value,
+) -> Result<Response<Body>, Error>
+{
+    debug!("handle_pledge_delete {:?}", body);
+
+    let user_id = body["data"]["relationships"]["patron"]["data"]["id"].as_str().ok_or_else(|| Error::malformed("invalid format (.data.relationships.patron.data.id)"))?;
+
+    deactivate_patron_licenses(config, keygen, revocations, user_id, config.patreon.pledge_delete_action)?;
+
+    Ok(Response::builder()
+        .status(http::StatusCode::OK)
+        .body(().into())
+        .unwrap())
+}
+
+/// Patreon v2 pledge create trigger (`members:pledge:create`)
+///
+/// Active patrons get a license by email.
+fn patreon_handle_member_create(
+    config: &Config,
+    keygen: &KeygenClient,
+    revocations: &dyn RevocationStore,
+    event: &MemberEvent,
+) -> Result<Response<Body>, Error>
+{
+    debug!("handle_member_create {:?}", event);
+
+    if event.patron_status() != Some(PatronStatus::ActivePatron) {
+        return patreon_handle_member_update(config, keygen, revocations, event);
+    }
+
+    let user_id = event.user_id().ok_or_else(|| Error::malformed("invalid format (.data.relationships.user.data.id)"))?;
+    let user_email = event.email().ok_or_else(|| Error::malformed("could not find patron email"))?;
+    issue_patron_license(config, keygen, revocations, user_id, user_email, member_policy(config, event))?;
+
+    Ok(Response::builder()
+        .status(http::StatusCode::OK)
+        .body(().into())
+        .unwrap())
+}
+
+/// Patreon v2 member triggers (`members:create`, `members:update`, `members:pledge:update`)
+///
+/// Active patrons have their existing licenses reinstated and moved to the policy of their
+/// tier, declined patrons have their licenses suspended, and former patrons are handled like
+/// deleted pledges. Licenses are only issued on `members:pledge:create`, so no email is sent
+/// here.
+fn patreon_handle_member_update(
+    config: &Config,
+    keygen: &KeygenClient,
+    revocations: &dyn RevocationStore,
+    event: &MemberEvent,
+) -> Result<Response<Body>, Error>
+{
+    debug!("handle_member_update {:?}", event);
+
+    let user_id = event.user_id().ok_or_else(|| Error::malformed("invalid format (.data.relationships.user.data.id)"))?;
+
+    match event.patron_status() {
+        Some(PatronStatus::ActivePatron) => {
+            let policy = member_policy(config, event);
+            let licenses = keygen.find_licenses_by_metadata("patreonUserId", user_id)?;
+            if licenses.is_empty() {
+                info!("no license found for patron {}, issued on members:pledge:create", user_id);
+            } else {
+                for license in licenses.iter() {
+                    if license["attributes"]["suspended"].as_bool().unwrap_or(false) {
+                        let key = keygen::reply_str(license, "/attributes/key")?;
+                        keygen.reinstate_license(key)?;
+                        revocations.remove(key)?;
+                    }
+                }
+                move_patron_licenses(keygen, user_id, policy)?;
+            }
+        }
+        Some(PatronStatus::DeclinedPatron) => {
+            deactivate_patron_licenses(config, keygen, revocations, user_id, DeactivationAction::Suspend)?;
+        }
+        Some(PatronStatus::FormerPatron) => {
+            deactivate_patron_licenses(config, keygen, revocations, user_id, config.patreon.pledge_delete_action)?;
+        }
+        status => {
+            debug!("ignoring member {} with patron status {:?}", event.data.id, status);
+        }
+    }
+
+    Ok(Response::builder()
+        .status(http::StatusCode::OK)
+        .body(().into())
+        .unwrap())
+}
+
+/// Patreon v2 member delete triggers (`members:delete`, `members:pledge:delete`)
+fn patreon_handle_member_delete(
+    config: &Config,
+    keygen: &KeygenClient,
+    revocations: &dyn RevocationStore,
+    event: &MemberEvent,
+) -> Result<Response<Body>, Error>
+{
+    debug!("handle_member_delete {:?}", event);
+
+    let user_id = event.user_id().ok_or_else(|| Error::malformed("invalid format (.data.relationships.user.data.id)"))?;
+
+    deactivate_patron_licenses(config, keygen, revocations, user_id, config.patreon.pledge_delete_action)?;
+
+    Ok(Response::builder()
+        .status(http::StatusCode::OK)
+        .body(().into())
+        .unwrap())
+}
+
+/// Returns the keygen policy for the tiers or amount of a member.
+fn member_policy<'a>(config: &'a Config, event: &MemberEvent) -> &'a str {
+    config
+        .patreon
+        .tier_policies
+        .policy(&event.tier_ids(), event.amount_cents())
+        .unwrap_or(&config.patreon.community_policy_id)
+}
+
+/// Sends a license to a patron by email.
+///
+/// If the patron already has a license (webhook retry, or re-pledge), that license is reinstated
+/// and sent again with a new activation token, instead of generating another one.
+fn issue_patron_license(
+    config: &Config,
+    keygen: &KeygenClient,
+    revocations: &dyn RevocationStore,
+    user_id: &str,
+    user_email: &str,
+    policy: &str,
+) -> Result<(), Error>
+{
+    debug!("patron email: {}", user_email);
+
+    let existing = keygen.find_licenses_by_metadata("patreonUserId", user_id)?;
+    let license = if let Some(existing) = existing.first() {
+        let license_id = keygen::reply_str(existing, "/id")?;
+        let key = keygen::reply_str(existing, "/attributes/key")?;
+        info!("patron {} already has license {}, sending it again", user_id, key);
+
+        if existing["attributes"]["suspended"].as_bool().unwrap_or(false) {
+            keygen.reinstate_license(key)?;
+            revocations.remove(key)?;
+        }
+        if existing["relationships"]["policy"]["data"]["id"].as_str() != Some(policy) {
+            keygen.change_license_policy(key, policy)?;
+        }
+
+        let activation_token = keygen.generate_activation_token(license_id)?;
+        format!("{}.{}", activation_token, key)
+    } else {
+        keygen.generate_license(
+            "PATREON",
+            policy,
+            None,
+            Some(user_id),
+            false)?
+    };
+
+    let email_body = format!(r##"Hi,
+
+
+Thank you for becoming our Patreon!
+
+You can activate your Flair Community license with the following key:
+{}
+
+For more information on how to install and activate your license, please refer to the documentation: https://docs.artineering.io/flair/setup/
+
+If you encounter any issues, please feel free to reach out to us through Discord, we are here to help.
+Have fun using Flair and make sure to share your results with the community.
+
+Cheers,
+
+
+Your team at Artineering."##, license);
+
+    // send the license to the patron
+    let email = Message::builder()
+        .from("Artineering <hello@artineering.io>".parse().unwrap())
+        .reply_to("Artineering <hello@artineering.io>".parse().unwrap())
+        .to(user_email
+            .parse()
+            .map_err(|_| Error::malformed("invalid patron email"))?)
+        .bcc("patreon@artineering.io".parse().unwrap())
+        .subject("[Flair] Your Community license key")
+        .body(email_body)
+        .map_err(|e| Error::Email(e.to_string()))?;
+
+    let creds = Credentials::new(config.smtp.username.clone(), config.smtp.password.clone());
+
+    let mailer = SmtpTransport::relay(&config.smtp.server)
+        .map_err(|e| Error::Email(e.to_string()))?
+        .credentials(creds)
+        .build();
+
+    mailer.send(&email).map_err(|e| Error::Email(e.to_string()))?;
+    info!("Email sent successfully");
+
+    Ok(())
+}
+
+/// Moves the licenses of a patron to a policy, keeping the license keys.
+///
+/// Returns the number of licenses found for the patron.
+fn move_patron_licenses(
+    keygen: &KeygenClient,
+    user_id: &str,
+    policy: &str,
+) -> Result<usize, Error>
+{
+    let licenses = keygen.find_licenses_by_metadata("patreonUserId", user_id)?;
+
+    for license in licenses.iter() {
+        let key = keygen::reply_str(license, "/attributes/key")?;
+        let current_policy = license["relationships"]["policy"]["data"]["id"].as_str();
+        if current_policy != Some(policy) {
+            info!("pledge updated by patron {}: moving license {} to policy {}", user_id, key, policy);
+            keygen.change_license_policy(key, policy)?;
+        }
+    }
+
+    Ok(licenses.len())
+}
+
+/// Deactivates the licenses of a patron, found through their `patreonUserId` metadata.
+fn deactivate_patron_licenses(
+    config: &Config,
+    keygen: &KeygenClient,
+    revocations: &dyn RevocationStore,
+    user_id: &str,
+    action: DeactivationAction,
+) -> Result<(), Error>
+{
+    let licenses = keygen.find_licenses_by_metadata("patreonUserId", user_id)?;
+    if licenses.is_empty() {
+        warn!("no license found for patron {}", user_id);
+    }
+
+    // pledges are charged at the beginning of the month, so the paid period ends with the month
+    let now = Utc::now();
+    let (year, month) = if now.month() == 12 { (now.year() + 1, 1) } else { (now.year(), now.month() + 1) };
+    let paid_until = Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).single().ok_or_else(|| Error::internal("invalid date"))?;
+
+    for license in licenses.iter() {
+        let key = keygen::reply_str(license, "/attributes/key")?;
+        info!("deactivating license {} of patron {} ({:?})", key, user_id, action);
+        deactivate_license(config, keygen, revocations, key, "PATREON", action, paid_until)?;
+    }
+
+    Ok(())
+}
+
+fn handle_webhook(
+    config: &Config,
+    fastspring: &FastSpringClient,
+    keygen: &KeygenClient,
+    revocations: &dyn RevocationStore,
+    state: &State,
+    req: Request,
+) -> Result<Response<Body>, Error> {
+    if !fastspring::authentify_web_hook(&req, &config.fastspring.webhook_secret) {
+        return Err(Error::InvalidSignature);
+    }
+
+    let body = util::body_str(req.body())?;
+    let events = Events::parse(body)?;
+    let newest = events.events.iter().filter_map(|e| e.created).max();
+    if let Some(created) = newest.and_then(|ms| Utc.timestamp_millis_opt(ms as i64).single()) {
+        state.replay.check_fresh(created)?;
+    }
+
+    // FastSpring considers the events listed in the reply as processed, the others are
+    // delivered again later
+    let mut acknowledged = Vec::new();
+    for e in events.events.iter() {
+        let outcome =
+            process_event(config, fastspring, keygen, revocations, &*state.processed_events, e);
+        match outcome {
+            EventOutcome::Processed => info!("event {} ({}) processed", e.id, e.ty),
+            EventOutcome::AlreadyProcessed => {
+                info!("event {} ({}) already processed, skipped", e.id, e.ty)
+            }
+            EventOutcome::InProgress => warn!(
+                "event {} ({}) being handled by another delivery, not acknowledged",
+                e.id, e.ty
+            ),
+            EventOutcome::Ignored => warn!("event {} ({}) ignored: unhandled type", e.id, e.ty),
+            EventOutcome::Rejected(ref err) => error!(
+                "event {} ({}) failed permanently, acknowledged: {} ({})",
+                e.id,
+                e.ty,
+                err,
+                err.kind()
+            ),
+            EventOutcome::Failed(ref err) => error!(
+                "event {} ({}) failed, not acknowledged: {} ({})",
+                e.id,
+                e.ty,
+                err,
+                err.kind()
+            ),
+        }
+        if outcome.is_acknowledged() {
+            acknowledged.push(e.id.as_str());
+        }
+    }
+    info!("acknowledged {}/{} events", acknowledged.len(), events.events.len());
+
+    Ok(Response::builder()
+        .status(http::StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(acknowledged.join("\n").into())
+        .unwrap())
+}
+
+/// Outcome of a single webhook event.
+enum EventOutcome {
+    /// Handled by this delivery
+    Processed,
+    /// Handled by an earlier delivery
+    AlreadyProcessed,
+    /// Being handled by another delivery, to be delivered again in case it fails
+    InProgress,
+    /// Event type we don't act on
+    Ignored,
+    /// Failed because of its payload, which delivering it again wouldn't fix
+    Rejected(Error),
+    /// To be delivered again
+    Failed(Error),
+}
+
+impl EventOutcome {
+    fn is_acknowledged(&self) -> bool {
+        !matches!(self, EventOutcome::InProgress | EventOutcome::Failed(_))
+    }
+}
+
+/// Handles a webhook event unless it has already been processed, recording it as processed.
+fn process_event(
+    config: &Config,
+    fastspring: &FastSpringClient,
+    keygen: &KeygenClient,
+    revocations: &dyn RevocationStore,
+    processed_events: &dyn ProcessedEventStore,
+    e: &RawEvent,
+) -> EventOutcome {
+    // claimed before handling, so that concurrent deliveries of the event don't both handle it
+    let claimed = match processed_events.claim(&e.id) {
+        Ok(Claim::Claimed) => true,
+        Ok(Claim::InProgress) => return EventOutcome::InProgress,
+        Ok(Claim::Done) => return EventOutcome::AlreadyProcessed,
+        Err(err) => {
+            warn!("could not claim event {}: {}", e.id, err);
+            false
+        }
+    };
+    let outcome = match handle_event(config, fastspring, keygen, revocations, e) {
+        Ok(outcome) => outcome,
+        // recorded as processed, otherwise FastSpring would deliver it again and again
+        Err(err) if err.is_caused_by_payload() => EventOutcome::Rejected(err),
+        Err(err) => {
+            // handled again when FastSpring delivers it again (or by the delivery taking the
+            // claim over once its lease expired, if releasing it fails), e.g. once an outage
+            // is over or the credentials are fixed
+            if claimed {
+                if let Err(release_err) = processed_events.release(&e.id) {
+                    error!("could not release event {}: {}", e.id, release_err);
+                }
+            }
+            return EventOutcome::Failed(err);
+        }
+    };
+    if claimed {
+        if let Err(err) = processed_events.complete(&e.id) {
+            warn!("could not record event {} as processed: {}", e.id, err);
+        }
+    }
+    outcome
+}
+
+/// Handles a single webhook event.
+fn handle_event(
+    config: &Config,
+    fastspring: &FastSpringClient,
+    keygen: &KeygenClient,
+    revocations: &dyn RevocationStore,
+    e: &RawEvent,
+) -> Result<EventOutcome, Error> {
+    match e.parse()? {
+        Event::SubscriptionDeactivated(subscription) => {
+            handle_subscription_deactivated(config, fastspring, keygen, revocations, &subscription)?;
+        }
+        Event::SubscriptionActivated(subscription)
+        | Event::SubscriptionUncanceled(subscription) => {
+            handle_subscription_reactivated(fastspring, keygen, revocations, &subscription)?;
+        }
+        Event::SubscriptionChargeFailed(charge) => {
+            handle_subscription_charge_failed(config, fastspring, keygen, &e.id, &charge)?;
+        }
+        Event::SubscriptionChargeCompleted(charge) => {
+            handle_subscription_charge_completed(config, fastspring, keygen, &charge)?;
+        }
+        Event::ReturnCreated(ret) => {
+            handle_return_created(config, fastspring, keygen, revocations, &ret)?;
+        }
+        _ => return Ok(EventOutcome::Ignored),
+    };
+    Ok(EventOutcome::Processed)
+}
+
+/// Handles deactivation of subscriptions.
+///
+/// Depending on the policy of each license associated with the original order, the license is
+/// either suspended (the default), revoked, or set to expire at the end of the paid period.
+/// Licenses to revoke are suspended right away, and only revoked by the scheduled handler once
+/// the grace period has passed.
+fn handle_subscription_deactivated(
+    config: &Config,
+    fastspring: &FastSpringClient,
+    keygen: &KeygenClient,
+    revocations: &dyn RevocationStore,
+    subscription: &Subscription,
+) -> Result<Response<Body>, Error> {
+    debug!("handle_subscription_deactivated {:?}", subscription);
+
+    let subscription_id = &subscription.id;
+    info!("subscription deactivated: {}", subscription_id);
+
+    let entries = fastspring.get_subscription_entries(subscription_id)?;
+    let licenses = order_licenses(original_order(&entries)?);
+
+    // the paid period ends with the last period paid by an order, or on deactivation
+    let paid_until = entries
+        .iter()
+        .filter_map(|entry| entry.end_period_date)
+        .max()
+        .or(subscription.deactivation_date)
+        .map(|ms| Utc.timestamp_millis_opt(ms).single().ok_or_else(|| Error::malformed("invalid period end date")))
+        .transpose()?
+        .unwrap_or_else(Utc::now);
+
+    for lic in licenses.iter() {
+        let key = license_key(lic)?;
+        let action = deactivation_action(config, keygen, key)?;
+        deactivate_license(config, keygen, revocations, key, subscription_id, action, paid_until)?;
+    }
+
+    Ok(Response::builder()
+        .status(http::StatusCode::OK)
+        .body(().into())
+        .unwrap())
+}
+
+/// Returns the deactivation action configured for the policy of a license.
+fn deactivation_action(
+    config: &Config,
+    keygen: &KeygenClient,
+    key: &str,
+) -> Result<DeactivationAction, Error> {
+    if config.deactivation.policy_actions.is_empty() {
+        return Ok(config.deactivation.action);
+    }
+    let policy = keygen.get_license_policy(key)?;
+    Ok(config.deactivation.action_for_policy(&policy))
+}
+
+/// Applies a deactivation action to a license.
+///
+/// Licenses to revoke are only suspended, and a pending revocation is recorded for the
+/// scheduled handler, unless there is no grace period.
+fn deactivate_license(
+    config: &Config,
+    keygen: &KeygenClient,
+    revocations: &dyn RevocationStore,
+    key: &str,
+    subscription_id: &str,
+    action: DeactivationAction,
+    paid_until: DateTime<Utc>,
+) -> Result<(), Error> {
+    match action {
+        DeactivationAction::Suspend => suspend_license(keygen, key)?,
+        DeactivationAction::Revoke if config.deactivation.revocation_grace_period_days <= 0 => {
+            revoke_license(keygen, key)?
+        }
+        DeactivationAction::Revoke => {
+            suspend_license(keygen, key)?;
+            revocations.add(&PendingRevocation {
+                license_key: key.to_string(),
+                subscription_id: subscription_id.to_string(),
+                revoke_after: Utc::now() + Duration::days(config.deactivation.revocation_grace_period_days),
+            })?
+        }
+        DeactivationAction::Expire => expire_license(keygen, key, paid_until)?,
+    }
+    Ok(())
+}
+
+/// Sets a license to expire at `paid_until`, keeping its former expiry in the metadata so that
+/// reactivation can restore it.
+fn expire_license(
+    keygen: &KeygenClient,
+    key: &str,
+    paid_until: DateTime<Utc>,
+) -> Result<(), Error> {
+    let license = keygen.get_license(key)?;
+    let mut metadata = license["attributes"]["metadata"]
+        .as_object()
+        .cloned()
+        .unwrap_or_default();
+    // already expiring after an earlier deactivation: its expiry is not the former one
+    if !metadata.contains_key(EXPIRY_BEFORE_DEACTIVATION_METADATA_KEY) {
+        metadata.insert(
+            EXPIRY_BEFORE_DEACTIVATION_METADATA_KEY.to_string(),
+            license["attributes"]["expiry"].clone(),
+        );
+        keygen.set_license_metadata(key, &metadata)?;
+    }
+    keygen.set_license_expiry(key, Some(paid_until))?;
+    Ok(())
+}
+
+/// Restores the expiry a license had before it was set to expire on deactivation.
+fn restore_license_expiry(
+    keygen: &KeygenClient,
+    key: &str,
+    mut metadata: serde_json::Map<String, serde_json::Value>,
+) -> Result<(), Error> {
+    let expiry = match metadata.remove(EXPIRY_BEFORE_DEACTIVATION_METADATA_KEY) {
+        Some(serde_json::Value::String(expiry)) => Some(
+            DateTime::parse_from_rfc3339(&expiry)
+                .map_err(|e| Error::malformed(format!("invalid former expiry of {}: {}", key, e)))?
+                .with_timezone(&Utc),
+        ),
+        _ => None,
+    };
+    keygen.set_license_expiry(key, expiry)?;
+    // the marker is removed last, so that a failure is retried
+    keygen.set_license_metadata(key, &metadata)?;
+    Ok(())
+}
+
+/// Suspends a license, doing nothing if it is already suspended.
+fn suspend_license(keygen: &KeygenClient, key: &str) -> Result<(), Error> {
+    match keygen.suspend_license(key) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.has_code("LICENSE_ALREADY_SUSPENDED") => {
+            info!("license {} is already suspended: {}", key, e);
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Revokes a license, doing nothing if it has already been revoked.
+fn revoke_license(keygen: &KeygenClient, key: &str) -> Result<(), Error> {
+    match keygen.revoke_license(key) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.is_not_found() => {
+            info!("license {} was already revoked", key);
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reinstates a license, doing nothing if it is not suspended.
+fn reinstate_license(keygen: &KeygenClient, key: &str) -> Result<(), Error> {
+    match keygen.reinstate_license(key) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.has_code("LICENSE_NOT_SUSPENDED") => {
+            info!("license {} is not suspended: {}", key, e);
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Handles reactivation of subscriptions.
+///
+/// This will reinstate the suspended licenses associated with the original order, cancel their
+/// pending revocations, and restore the expiry of those set to expire on deactivation.
+/// `subscription.activated` is also sent for new subscriptions, whose licenses are left alone.
+fn handle_subscription_reactivated(
+    fastspring: &FastSpringClient,
+    keygen: &KeygenClient,
+    revocations: &dyn RevocationStore,
+    subscription: &Subscription,
+) -> Result<Response<Body>, Error> {
+    debug!("handle_subscription_reactivated {:?}", subscription);
+
+    let subscription_id = &subscription.id;
+    info!("subscription reactivated: {}", subscription_id);
+
+    let licenses_to_reinstate = original_order_licenses(fastspring, subscription_id)?;
+
+    for lic in licenses_to_reinstate.iter() {
+        let key = license_key(lic)?;
+        let license = match keygen.get_license(key) {
+            Ok(license) => license,
+            // e.g. deleted by hand, or revoked after the grace period
+            Err(ref e) if e.is_not_found() => {
+                warn!("license {} of subscription {} not found, skipped", key, subscription_id);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let suspended = license["attributes"]["suspended"].as_bool().unwrap_or(false);
+        let pending = revocations.is_pending(key)?;
+        let metadata = license["attributes"]["metadata"]
+            .as_object()
+            .cloned()
+            .unwrap_or_default();
+        let expiring = metadata.contains_key(EXPIRY_BEFORE_DEACTIVATION_METADATA_KEY);
+        if !suspended && !pending && !expiring {
+            debug!("license {} is neither suspended, pending revocation nor expiring", key);
+            continue;
+        }
+        if expiring {
+            restore_license_expiry(keygen, key, metadata)?;
+        }
+        // cancel the revocation first, so that a failure doesn't leave an active license
+        // to be revoked
+        if pending {
+            revocations.remove(key)?;
+        }
+        if suspended {
+            reinstate_license(keygen, key)?;
+        }
+    }
+
+    Ok(Response::builder()
+        .status(http::StatusCode::OK)
+        .body(().into())
+        .unwrap())
+}
+
+/// Handles failed subscription charges.
+///
+/// The number of consecutive failed charges is tracked in the license metadata. Once it
+/// reaches `FAILED_CHARGES_BEFORE_SUSPENSION`, the licenses are suspended. The ID of the event is
+/// recorded along with the count, so that a redelivered event is only counted once per license.
+fn handle_subscription_charge_failed(
+    config: &Config,
+    fastspring: &FastSpringClient,
+    keygen: &KeygenClient,
+    event_id: &str,
+    charge: &Charge,
+) -> Result<Response<Body>, Error> {
+    debug!("handle_subscription_charge_failed {:?}", charge);
+
+    let subscription_id = charge.subscription.id();
+    info!(
+        "subscription charge failed: {} (reason: {})",
+        subscription_id,
+        charge.reason.as_deref().unwrap_or("unknown")
+    );
+
+    let licenses = original_order_licenses(fastspring, subscription_id)?;
+
+    for lic in licenses.iter() {
+        let key = license_key(lic)?;
+        let mut metadata = keygen.get_license_metadata(key)?;
+        if metadata.get(LAST_FAILED_CHARGE_METADATA_KEY).and_then(|v| v.as_str()) == Some(event_id)
+        {
+            info!("failed charge {} already counted for license {}", event_id, key);
+            continue;
+        }
+        let failed_charges = failed_charges(&metadata) + 1;
+        if failed_charges >= config.deactivation.failed_charges_before_suspension {
+            info!("suspending license {} after {} failed charges", key, failed_charges);
+            suspend_license(keygen, key)?;
+        }
+        // only counted once the license is suspended, so that the suspension is retried if it
+        // fails
+        metadata.insert(FAILED_CHARGES_METADATA_KEY.to_string(), failed_charges.into());
+        metadata.insert(LAST_FAILED_CHARGE_METADATA_KEY.to_string(), event_id.into());
+        keygen.set_license_metadata(key, &metadata)?;
+    }
+
+    Ok(Response::builder()
+        .status(http::StatusCode::OK)
+        .body(().into())
+        .unwrap())
+}
+
+/// Handles successful subscription charges.
+///
+/// This resets the failed charge count of the licenses, and reinstates them if they were
+/// suspended because of failed charges.
+fn handle_subscription_charge_completed(
+    config: &Config,
+    fastspring: &FastSpringClient,
+    keygen: &KeygenClient,
+    charge: &Charge,
+) -> Result<Response<Body>, Error> {
+    debug!("handle_subscription_charge_completed {:?}", charge);
+
+    let subscription_id = charge.subscription.id();
+    info!("subscription charge completed: {}", subscription_id);
+
+    let licenses = original_order_licenses(fastspring, subscription_id)?;
+
+    for lic in licenses.iter() {
+        let key = license_key(lic)?;
+        let mut metadata = keygen.get_license_metadata(key)?;
+        let failed_charges = failed_charges(&metadata);
+        if failed_charges == 0 {
+            continue;
+        }
+        metadata.insert(FAILED_CHARGES_METADATA_KEY.to_string(), 0.into());
+        keygen.set_license_metadata(key, &metadata)?;
+        if failed_charges >= config.deactivation.failed_charges_before_suspension {
+            reinstate_license(keygen, key)?;
+        }
+    }
+
+    Ok(Response::builder()
+        .status(http::StatusCode::OK)
+        .body(().into())
+        .unwrap())
+}
+
+/// Handles refunds and chargebacks.
+///
+/// Licenses of refunded items are deactivated like those of deactivated subscriptions, i.e.
+/// according to their policy. Licenses of charged back items are only suspended, since
+/// chargebacks can still be disputed.
+fn handle_return_created(
+    config: &Config,
+    fastspring: &FastSpringClient,
+    keygen: &KeygenClient,
+    revocations: &dyn RevocationStore,
+    ret: &Return,
+) -> Result<Response<Body>, Error> {
+    debug!("handle_return_created {:?}", ret);
+
+    info!(
+        "{} created: {} (order {}, reason: {})",
+        if ret.is_chargeback() { "chargeback" } else { "return" },
+        ret.id,
+        ret.original.id,
+        ret.reason.as_deref().unwrap_or("unknown")
+    );
+
+    let order = fastspring.get_order(&ret.original.id)?;
+    let licenses = returned_licenses(fastspring, &order, &ret.items)?;
+
+    for lic in licenses.iter() {
+        let key = license_key(lic)?;
+        if ret.is_chargeback() {
+            suspend_license(keygen, key)?;
+        } else {
+            let action = deactivation_action(config, keygen, key)?;
+            deactivate_license(config, keygen, revocations, key, &order.id, action, Utc::now())?;
+        }
+    }
+
+    Ok(Response::builder()
+        .status(http::StatusCode::OK)
+        .body(().into())
+        .unwrap())
+}
+
+/// Returns the license codes affected by a (possibly partial) return.
+///
+/// For each returned item, only as many licenses as the returned quantity are taken from the
+/// matching order item. Subscription rebills have no fulfillments of their own, so for those the
+/// licenses of the original subscription order are used instead.
+fn returned_licenses(
+    fastspring: &FastSpringClient,
+    order: &Order,
+    returned_items: &[ReturnItem],
+) -> Result<Vec<String>, Error> {
+    let mut licenses = Vec::new();
+    for returned in returned_items.iter() {
+        let item = match returned_order_item(order, returned) {
+            Some(item) => item,
+            None => {
+                warn!("returned item {:?} not found in order {}", returned.product, order.id);
+                continue;
+            }
+        };
+
+        let mut item_licenses: Vec<String> =
+            item.licenses().into_iter().map(String::from).collect();
+        if item_licenses.is_empty() {
+            if let Some(ref subscription) = item.subscription {
+                item_licenses = original_order_licenses(fastspring, subscription.id())?;
+            }
+        }
+        licenses.extend(returned_quantity(item_licenses, returned.quantity));
+    }
+    Ok(licenses)
+}
+
+/// Returns the order item of a returned item, matched by product.
+fn returned_order_item<'a>(order: &'a Order, returned: &ReturnItem) -> Option<&'a Item> {
+    let product = returned.product.as_deref()?;
+    order
+        .items
+        .iter()
+        .find(|item| item.product.as_deref() == Some(product))
+}
+
+/// Returns the licenses of a returned quantity of an item (the last ones), or all of them if
+/// the whole item is returned.
+fn returned_quantity(mut licenses: Vec<String>, quantity: Option<u32>) -> Vec<String> {
+    let quantity = quantity
+        .map(|q| q as usize)
+        .unwrap_or_else(|| licenses.len())
+        .min(licenses.len());
+    let first = licenses.len() - quantity;
+    licenses.split_off(first)
+}
+
+/// Returns the number of consecutive failed charges recorded in license metadata.
+fn failed_charges(metadata: &serde_json::Map<String, serde_json::Value>) -> u32 {
+    metadata
+        .get(FAILED_CHARGES_METADATA_KEY)
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0) as u32
+}
+
+/// Returns the license codes fulfilled by the order that created the subscription.
+fn original_order_licenses(
+    fastspring: &FastSpringClient,
+    subscription_id: &str,
+) -> Result<Vec<String>, Error> {
+    let entries = fastspring.get_subscription_entries(subscription_id)?;
+    Ok(order_licenses(original_order(&entries)?))
+}
+
+/// Returns the order that created the subscription.
+fn original_order(entries: &[SubscriptionEntry]) -> Result<&Order, Error> {
+    // according to the API, this is the entry whose ".reference" field does not include
+    // a "B" (for "billing") at the end. All the others are subscription billing orders.
+    Ok(entries
+        .iter()
+        .map(|entry| &entry.order)
+        .find(|order| !order.is_rebill())
+        .ok_or_else(|| {
+            FastSpringError::InvalidReply("could not find original order".to_string())
+        })?)
+}
+
+/// Returns the license codes fulfilled by an order.
+fn order_licenses(order: &Order) -> Vec<String> {
+    order
+        .items
+        .iter()
+        .flat_map(|item| item.licenses())
+        .map(String::from)
+        .collect()
+}
+
+/// Handles license creation requests (coming from FastSpring), whose signature was checked by
+/// `route`.
+fn handle_keygen_create(
+    keygen: &KeygenClient,
+    req: Request,
+) -> Result<Response<Body>, Error> {
+    let params: HashMap<_, _> =
+        url::form_urlencoded::parse(util::body_bytes(req.body())).collect();
+    //debug!("params = {:?}", params);
+    let subscription = params
+        .get("subscription")
+        .ok_or_else(|| Error::malformed("invalid query parameters (no subscription)"))?;
+    let policy_id = params
+        .get("policy")
+        .ok_or_else(|| Error::malformed("invalid query parameters (no policy)"))?;
+    let quantity: u32 = params
+        .get("quantity")
+        .ok_or_else(|| Error::malformed("invalid query parameters (no quantity)"))?
+        .parse()
+        .map_err(|_| Error::malformed("invalid query parameters (quantity)"))?;
+
+    let codes = keygen
+        .generate_licenses(subscription, policy_id, quantity, None, false)?
+        .join("\n");
+
+    Ok(text_response(codes))
+}
+
+fn not_found(_req: Request) -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(http::StatusCode::NOT_FOUND)
+        .body(Body::default())
+        .unwrap())
+}
+
+fn not_allowed(_req: Request) -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(http::StatusCode::METHOD_NOT_ALLOWED)
+        .body(Body::default())
+        .unwrap())
+}
+
+/// Revokes the licenses whose grace period has passed at `now`.
+pub fn revoke_due_licenses(state: &State, now: DateTime<Utc>) -> Result<(), Error> {
+    let (keygen, revocations) = (&state.keygen, &state.revocations);
+    let due = revocations.due(now)?;
+    let mut failed = 0;
+    for revocation in due.iter() {
+        info!(
+            "grace period of license {} (subscription {}) ended on {}, revoking",
+            revocation.license_key, revocation.subscription_id, revocation.revoke_after
+        );
+        // one license that cannot be revoked must not block the others
+        let result = revoke_license(keygen, &revocation.license_key)
+            .and_then(|()| revocations.remove(&revocation.license_key));
+        if let Err(e) = result {
+            error!("could not revoke license {}: {} ({})", revocation.license_key, e, e.kind());
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(Error::internal(format!("could not revoke {}/{} licenses", failed, due.len())));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes(codes: &[&str]) -> Vec<String> {
+        codes.iter().map(|c| c.to_string()).collect()
+    }
+
+    fn returned_item(product: Option<&str>, quantity: Option<u32>) -> ReturnItem {
+        ReturnItem {
+            product: product.map(String::from),
+            quantity,
+        }
+    }
+
+    #[test]
+    fn partial_returns_take_the_last_licenses() {
+        let licenses = codes(&["a", "b", "c"]);
+        assert_eq!(returned_quantity(licenses.clone(), Some(1)), codes(&["c"]));
+        assert_eq!(returned_quantity(licenses.clone(), Some(2)), codes(&["b", "c"]));
+        assert_eq!(returned_quantity(licenses.clone(), Some(0)), codes(&[]));
+    }
+
+    #[test]
+    fn whole_returns_take_all_licenses() {
+        let licenses = codes(&["a", "b", "c"]);
+        assert_eq!(returned_quantity(licenses.clone(), None), licenses);
+        assert_eq!(returned_quantity(licenses.clone(), Some(5)), licenses);
+        assert_eq!(returned_quantity(Vec::new(), Some(1)), codes(&[]));
+    }
+
+    #[test]
+    fn returned_items_are_matched_by_product() {
+        let order: Order = serde_json::from_value(serde_json::json!({
+            "id": "order",
+            "items": [{ "quantity": 1 }, { "product": "app", "quantity": 2 }]
+        }))
+        .unwrap();
+        let item = returned_order_item(&order, &returned_item(Some("app"), None)).unwrap();
+        assert_eq!(item.quantity, 2);
+        assert!(returned_order_item(&order, &returned_item(Some("other"), None)).is_none());
+        // an item without product doesn't match the order item without product
+        assert!(returned_order_item(&order, &returned_item(None, Some(1))).is_none());
+    }
+}
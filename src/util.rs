@@ -1,11 +1,76 @@
 use lambda_http::Body;
-use lambda_runtime::error::HandlerError;
+use crate::error::Error;
+use serde::de::DeserializeOwned;
 use serde_json::Value;
+use std::fmt;
+use subtle::ConstantTimeEq;
 
-pub fn body_to_json(body: &Body) -> Result<Value, HandlerError> {
-    Ok(serde_json::from_str(match body {
-        Body::Text(ref s) => s,
-        _ => return Err("invalid json".into()),
+/// Returns the bytes of a request body, as signed by the sender.
+///
+/// Bodies of API Gateway and ALB events flagged `isBase64Encoded` (e.g. with binary media types)
+/// are already decoded into `Body::Binary`, so these are the original bytes in all cases.
+pub fn body_bytes(body: &Body) -> &[u8] {
+    match body {
+        Body::Empty => &[],
+        Body::Text(s) => s.as_bytes(),
+        Body::Binary(b) => b,
+    }
+}
+
+/// Returns a request body as text.
+pub fn body_str(body: &Body) -> Result<&str, Error> {
+    std::str::from_utf8(body_bytes(body))
+        .map_err(|e| Error::malformed(format!("invalid body: {}", e)))
+}
+
+pub fn body_to_json(body: &Body) -> Result<Value, Error> {
+    serde_json::from_str(body_str(body)?)
+        .map_err(|e| Error::malformed(format!("invalid json: {}", e)))
+}
+
+/// Compares two byte strings in constant time (for a given length), to check signatures
+/// without leaking how much of them matched.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// Error returned when a payload doesn't match the expected shape.
+#[derive(Debug)]
+pub struct ParseError {
+    /// What was being parsed (e.g. the event type)
+    pub context: String,
+    /// Path to the offending field (e.g. `items[0].fulfillments`)
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid format ({}: .{}): {}",
+            self.context, self.path, self.message
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Deserializes `value`, reporting the path of the offending field on failure.
+pub fn from_value<T: DeserializeOwned>(context: &str, value: Value) -> Result<T, ParseError> {
+    serde_path_to_error::deserialize(value).map_err(|e| ParseError {
+        context: context.to_string(),
+        path: e.path().to_string(),
+        message: e.into_inner().to_string(),
+    })
+}
+
+/// Deserializes a JSON string, reporting the path of the offending field on failure.
+pub fn from_str<T: DeserializeOwned>(context: &str, s: &str) -> Result<T, ParseError> {
+    let de = &mut serde_json::Deserializer::from_str(s);
+    serde_path_to_error::deserialize(de).map_err(|e| ParseError {
+        context: context.to_string(),
+        path: e.path().to_string(),
+        message: e.into_inner().to_string(),
     })
-    .map_err(|_| "invalid json")?)
 }
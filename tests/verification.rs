@@ -0,0 +1,237 @@
+//! Fuzz-style corpus for the request verification paths: whatever the headers and body, the
+//! verifiers must reject bad requests without panicking.
+use fastspring_keygen_integration::secrets::Secrets;
+use fastspring_keygen_integration::{fastspring, patreon, util};
+use hmac::{Hmac, Mac, NewMac};
+use http::header::HeaderValue;
+use lambda_http::{Body, Request};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::json;
+
+const SECRET: &str = "secret";
+
+fn secrets() -> Secrets {
+    SECRET.parse().unwrap()
+}
+
+fn request(headers: &[(&str, HeaderValue)], body: Body) -> Request {
+    let mut builder = http::Request::builder();
+    builder.method("POST").uri("https://example.com/");
+    for (name, value) in headers {
+        builder.header(*name, value.clone());
+    }
+    builder.body(body).unwrap()
+}
+
+/// An API Gateway (ALB) event, as received by the lambda.
+fn gateway_request(mut headers: serde_json::Value, body: &str, is_base64_encoded: bool) -> Request {
+    headers["host"] = json!("example.com");
+    let event = json!({
+        "requestContext": { "elb": { "targetGroupArn": "arn:aws:elasticloadbalancing:test" } },
+        "httpMethod": "POST",
+        "path": "/",
+        "queryStringParameters": {},
+        "headers": headers,
+        "isBase64Encoded": is_base64_encoded,
+        "body": body,
+    });
+    lambda_http::request::from_str(&event.to_string()).unwrap()
+}
+
+fn fastspring_signature(body: &[u8]) -> String {
+    base64::encode(&hmac_sha256::HMAC::mac(body, SECRET.as_bytes()))
+}
+
+fn patreon_signature(body: &[u8]) -> String {
+    let mut mac = Hmac::<other_md5::Md5>::new_varkey(SECRET.as_bytes()).unwrap();
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Signature header values that must all be rejected.
+fn invalid_signatures(rng: &mut StdRng) -> Vec<HeaderValue> {
+    let mut values: Vec<HeaderValue> = vec![
+        "",
+        " ",
+        "z",
+        "abc",
+        "zz",
+        "0",
+        "00",
+        "0x00",
+        "==",
+        "====",
+        "not base64!",
+        "AAAA",
+        "ffffffffffffffffffffffffffffffff",
+        "ffffffffffffffffffffffffffffffffff",
+        "é",
+        "\u{1F600}",
+    ]
+    .into_iter()
+    .map(|s| HeaderValue::from_bytes(s.as_bytes()).unwrap())
+    .collect();
+    values.push(HeaderValue::from_bytes(b"\xff\xfe").unwrap());
+    values.push(HeaderValue::from_str(&"a".repeat(10_000)).unwrap());
+    for _ in 0..200 {
+        let len = rng.gen_range(0, 80);
+        let bytes: Vec<u8> = (0..len)
+            .map(|_| rng.gen_range(0x20, 0x100) as u8)
+            .filter(|&b| b != 0x7f)
+            .collect();
+        values.push(HeaderValue::from_bytes(&bytes).unwrap());
+    }
+    values
+}
+
+/// `Body` is not `Clone`.
+fn copy(body: &Body) -> Body {
+    match body {
+        Body::Empty => Body::Empty,
+        Body::Text(s) => Body::Text(s.clone()),
+        Body::Binary(b) => Body::Binary(b.clone()),
+    }
+}
+
+/// Bodies of various kinds, including invalid UTF-8.
+fn bodies(rng: &mut StdRng) -> Vec<Body> {
+    let mut bodies = vec![
+        Body::Empty,
+        Body::Text(String::new()),
+        Body::Text("{".to_string()),
+        Body::Text("{\"events\":[]}".to_string()),
+        Body::Text("security_request_hash=&a=b".to_string()),
+        Body::Binary(vec![0xff, 0xfe, 0x00]),
+        Body::Binary(Vec::new()),
+    ];
+    for _ in 0..50 {
+        let len = rng.gen_range(0, 512);
+        bodies.push(Body::Binary((0..len).map(|_| rng.gen()).collect()));
+    }
+    bodies
+}
+
+#[test]
+fn invalid_headers_are_rejected() {
+    let mut rng = StdRng::seed_from_u64(1);
+    let secrets = secrets();
+    for signature in invalid_signatures(&mut rng) {
+        for body in bodies(&mut rng) {
+            let fs = request(&[("X-FS-Signature", signature.clone())], copy(&body));
+            assert!(!fastspring::authentify_web_hook(&fs, &secrets));
+            let patreon = request(&[("X-Patreon-Signature", signature.clone())], body);
+            assert!(!patreon::authentify_web_hook(&patreon, &secrets));
+        }
+    }
+}
+
+#[test]
+fn missing_headers_are_rejected() {
+    let mut rng = StdRng::seed_from_u64(2);
+    let secrets = secrets();
+    for body in bodies(&mut rng) {
+        let req = request(&[], body);
+        assert!(!fastspring::authentify_web_hook(&req, &secrets));
+        assert!(!patreon::authentify_web_hook(&req, &secrets));
+        assert!(!fastspring::verify_license_gen(&req, &secrets));
+        let _ = util::body_to_json(req.body());
+    }
+}
+
+#[test]
+fn license_gen_parameters_are_rejected() {
+    let mut rng = StdRng::seed_from_u64(3);
+    let secrets = secrets();
+    let mut queries = vec![
+        String::new(),
+        "security_request_hash".to_string(),
+        "security_request_hash=".to_string(),
+        "security_request_hash=%ZZ&%=%".to_string(),
+        "a=1&a=2&security_request_hash=00".to_string(),
+        "&&&===".to_string(),
+    ];
+    for _ in 0..200 {
+        let len = rng.gen_range(0, 100);
+        queries.push((0..len).map(|_| rng.gen_range(0x20u8, 0x7f) as char).collect());
+    }
+    for query in queries {
+        let req = request(&[], Body::Text(query));
+        assert!(!fastspring::verify_license_gen(&req, &secrets));
+    }
+}
+
+#[test]
+fn signed_requests_are_accepted() {
+    let secrets = secrets();
+    for body in &[&b""[..], b"{\"events\":[]}", b"\xff\x00binary"] {
+        let header = HeaderValue::from_str(&fastspring_signature(body)).unwrap();
+        let req = request(&[("X-FS-Signature", header)], Body::from(body.to_vec()));
+        assert!(fastspring::authentify_web_hook(&req, &secrets));
+
+        let header = HeaderValue::from_str(&patreon_signature(body)).unwrap();
+        let req = request(&[("X-Patreon-Signature", header)], Body::from(body.to_vec()));
+        assert!(patreon::authentify_web_hook(&req, &secrets));
+    }
+}
+
+#[test]
+fn base64_gateway_bodies_are_verified_on_decoded_bytes() {
+    let secrets = secrets();
+    let body = b"{\"events\":[]}";
+    let encoded = base64::encode(&body[..]);
+
+    let headers = json!({ "x-fs-signature": fastspring_signature(body) });
+    assert!(fastspring::authentify_web_hook(&gateway_request(headers, &encoded, true), &secrets));
+
+    let headers = json!({ "x-patreon-signature": patreon_signature(body) });
+    assert!(patreon::authentify_web_hook(&gateway_request(headers, &encoded, true), &secrets));
+
+    // signed over the encoded text rather than the body
+    let headers = json!({ "x-fs-signature": fastspring_signature(encoded.as_bytes()) });
+    assert!(!fastspring::authentify_web_hook(&gateway_request(headers, &encoded, true), &secrets));
+}
+
+#[test]
+fn invalid_base64_gateway_bodies_are_rejected() {
+    let secrets = secrets();
+    for body in &["", "!!!", "AAA", "====", "\u{1F600}"] {
+        for signature in &["", "00", "zz", "AAAA"] {
+            let headers = json!({
+                "x-fs-signature": signature,
+                "x-patreon-signature": signature,
+            });
+            let req = gateway_request(headers, body, true);
+            assert!(!fastspring::authentify_web_hook(&req, &secrets));
+            assert!(!patreon::authentify_web_hook(&req, &secrets));
+            assert!(!fastspring::verify_license_gen(&req, &secrets));
+            assert!(util::body_to_json(req.body()).is_err());
+        }
+    }
+}
+
+#[test]
+fn license_gen_bodies_are_verified_in_any_encoding() {
+    let secrets = secrets();
+    let digest = md5::compute(format!("{}{}{}", "1", "sub", SECRET).as_bytes());
+    let query = format!("quantity=1&subscription=sub&security_request_hash={:032x}", digest);
+
+    let text = request(&[], Body::Text(query.clone()));
+    assert!(fastspring::verify_license_gen(&text, &secrets));
+    let binary = request(&[], Body::Binary(query.clone().into_bytes()));
+    assert!(fastspring::verify_license_gen(&binary, &secrets));
+    let encoded = gateway_request(json!({}), &base64::encode(&query), true);
+    assert!(fastspring::verify_license_gen(&encoded, &secrets));
+}
+
+#[test]
+fn json_bodies_are_read_in_any_encoding() {
+    let body = "{\"events\":[]}";
+    let expected = json!({ "events": [] });
+    assert_eq!(util::body_to_json(&Body::Text(body.to_string())).unwrap(), expected);
+    assert_eq!(util::body_to_json(&Body::from(body.as_bytes().to_vec())).unwrap(), expected);
+    let req = gateway_request(json!({}), &base64::encode(body), true);
+    assert_eq!(util::body_to_json(req.body()).unwrap(), expected);
+    let req = gateway_request(json!({}), body, false);
+    assert_eq!(util::body_to_json(req.body()).unwrap(), expected);
+}